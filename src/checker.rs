@@ -0,0 +1,394 @@
+use crate::{
+    cpu::{CPU, MAX_REGISTERS},
+    diagnostic::{Diagnostic, Severity},
+    insts::{validate_operands, Instructions},
+};
+use std::collections::{HashMap, HashSet};
+
+struct Checker<'a> {
+    cpu: &'a CPU,
+    /// Aliases visible from every block.
+    globals: HashSet<String>,
+    /// Block-local aliases and the first block declaring each of them.
+    locals: HashMap<String, String>,
+    /// Aliases declared by the block being checked, anywhere in it.
+    own: HashSet<String>,
+    diagnostics: Vec<Diagnostic>,
+    block: String,
+}
+
+impl Checker<'_> {
+    fn report(&mut self, severity: Severity, index: usize, message: String) {
+        let span = self
+            .cpu
+            .spans
+            .get(&self.block)
+            .and_then(|spans| spans.get(index).copied());
+        self.diagnostics.push(Diagnostic {
+            severity,
+            file: self.cpu.files.get(&self.block).cloned(),
+            block: Some(self.block.clone()),
+            ..Diagnostic::error(span, message)
+        });
+    }
+    fn check_register(&mut self, index: usize, token: &Instructions) {
+        if let Instructions::REG(rid) = token {
+            let limit = if self.cpu.grow_registers {
+                MAX_REGISTERS
+            } else {
+                self.cpu.registers.len()
+            };
+            if *rid >= limit {
+                self.report(
+                    Severity::Error,
+                    index,
+                    format!(
+                        "register r{} is out of bounds, only {} registers are available",
+                        rid, limit
+                    ),
+                );
+            }
+        }
+    }
+    /// Checks that a name used as an operand resolves to a declared alias.
+    fn check_alias(&mut self, index: usize, token: &Instructions, declared: &HashSet<String>) {
+        let Instructions::IDENT(alias) = token else {
+            return;
+        };
+        if declared.contains(alias) {
+            return;
+        }
+        let message = if self.own.contains(alias) {
+            format!("alias `{}` is used before it is declared", alias)
+        } else if let Some(owner) = self.locals.get(alias) {
+            format!(
+                "undeclared alias `{}`, it is local to block `{}`",
+                alias, owner
+            )
+        } else {
+            format!("undeclared alias `{}`", alias)
+        };
+        self.report(Severity::Error, index, message);
+    }
+    fn check_block(&mut self, name: &str, block: &[Instructions]) {
+        self.block = name.to_string();
+        let mut declared = self.globals.clone();
+        // aliases of this block declared with `declare ro` so far
+        let mut read_only = HashSet::new();
+        self.own = declarations(block)
+            .into_iter()
+            .map(|(_, alias)| alias)
+            .collect();
+        for (index, problem) in validate_operands(block) {
+            self.report(Severity::Error, index, problem);
+        }
+        let mut i = 0;
+        while i < block.len() {
+            let token = &block[i];
+            let Some(descriptor) = token.descriptor() else {
+                i += 1;
+                continue;
+            };
+            let mut count = descriptor.arity();
+            if *token == Instructions::TIMES && block.get(i + 2) == Some(&Instructions::AS) {
+                count += 2;
+            }
+            let operands: Vec<&Instructions> = block
+                .iter()
+                .skip(i + 1)
+                .take(count)
+                .take_while(|t| t.operand_kind().is_some() || **t == Instructions::AS)
+                .collect();
+            for (offset, operand) in operands.iter().enumerate() {
+                self.check_register(i + 1 + offset, operand);
+                // `run` takes a block name and `declare` introduces its alias
+                let names_alias = *token == Instructions::RUN
+                    || (matches!(
+                        token,
+                        Instructions::DECLARE | Instructions::DECLARE_RO | Instructions::GLOBAL
+                    ) && offset == 1);
+                if !names_alias {
+                    self.check_alias(i + 1 + offset, operand, &declared);
+                }
+                if let Instructions::IDENT(alias) = operand {
+                    if descriptor.writes(offset) && read_only.contains(alias) {
+                        self.report(
+                            Severity::Error,
+                            i + 1 + offset,
+                            format!("`{}` is read-only", alias),
+                        );
+                    }
+                }
+            }
+            match (token, operands.as_slice()) {
+                (
+                    Instructions::DECLARE | Instructions::DECLARE_RO,
+                    [Instructions::REG(_), Instructions::IDENT(alias)],
+                ) => {
+                    if self.globals.contains(alias) {
+                        self.report(
+                            Severity::Warning,
+                            i + 2,
+                            format!("alias `{}` shadows a global of the same name", alias),
+                        );
+                    }
+                    if *token == Instructions::DECLARE_RO {
+                        read_only.insert(alias.clone());
+                    } else {
+                        read_only.remove(alias);
+                    }
+                    declared.insert(alias.clone());
+                }
+                (Instructions::RUN, [Instructions::IDENT(target)])
+                    if !self.cpu.blocks.contains_key(target) =>
+                {
+                    self.report(
+                        Severity::Error,
+                        i,
+                        format!("`run {}` refers to a block that does not exist", target),
+                    );
+                }
+
+                (Instructions::BREAK | Instructions::CONTINUE | Instructions::HALT, _) => {
+                    let after = i + 1 + operands.len();
+                    let next = block[after..]
+                        .iter()
+                        .position(|t| *t != Instructions::EOL)
+                        .map(|offset| after + offset);
+                    if let Some(next) = next {
+                        if !matches!(
+                            block[next],
+                            Instructions::ELSE
+                                | Instructions::ENDIF
+                                | Instructions::ENDWHILE
+                                | Instructions::ENDTIMES
+                                | Instructions::ENDFOR
+                        ) {
+                            self.report(
+                                Severity::Warning,
+                                next,
+                                format!("unreachable code after `{}`", token),
+                            );
+                        }
+                    }
+                }
+                _ => {}
+            }
+            i += 1 + operands.len();
+        }
+    }
+}
+
+/// Every `declare`, `declare ro` and `global` of a block with its alias.
+fn declarations(block: &[Instructions]) -> Vec<(&Instructions, String)> {
+    block
+        .windows(3)
+        .filter_map(|window| match window {
+            [kind @ (Instructions::DECLARE | Instructions::DECLARE_RO | Instructions::GLOBAL), Instructions::REG(_), Instructions::IDENT(alias)] => {
+                Some((kind, alias.clone()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+impl CPU {
+    /// Statically validates every loaded block without running anything and
+    /// returns all problems found, sorted by block name.
+    pub fn check(&self) -> Vec<Diagnostic> {
+        let mut names: Vec<&String> = self.blocks.keys().collect();
+        names.sort();
+        let mut checker = self.checker();
+        let referenced: HashSet<&String> = self
+            .blocks
+            .values()
+            .flat_map(|block| block.windows(2))
+            .filter_map(|window| match window {
+                [Instructions::RUN, Instructions::IDENT(target)] => Some(target),
+                _ => None,
+            })
+            .collect();
+        for name in names {
+            checker.check_block(name, &self.blocks[name]);
+            if *name != self.entry && !name.contains("::") && !referenced.contains(name) {
+                checker.report(
+                    Severity::Warning,
+                    0,
+                    format!("block `{}` is never run", name),
+                );
+            }
+        }
+        checker.diagnostics
+    }
+    /// Reports `times N as name` and `for name, ...` loops in the blocks
+    /// `names` whose counter is not a declared alias. Unlike the rest of
+    /// `check`, this runs whenever blocks are loaded, since such a loop can
+    /// never start.
+    pub(crate) fn check_counters(&self, names: &[String]) -> Vec<Diagnostic> {
+        let mut checker = self.checker();
+        for name in names {
+            let Some(block) = self.blocks.get(name) else {
+                continue;
+            };
+            checker.block = name.clone();
+            checker.own = declarations(block)
+                .into_iter()
+                .map(|(_, alias)| alias)
+                .collect();
+            let mut declared = checker.globals.clone();
+            for (i, token) in block.iter().enumerate() {
+                let counter = match token {
+                    Instructions::DECLARE | Instructions::DECLARE_RO => {
+                        if let Some(Instructions::IDENT(alias)) = block.get(i + 2) {
+                            declared.insert(alias.clone());
+                        }
+                        None
+                    }
+                    Instructions::TIMES if block.get(i + 2) == Some(&Instructions::AS) => {
+                        Some(i + 3)
+                    }
+                    Instructions::FOR => Some(i + 1),
+                    _ => None,
+                };
+                if let Some(counter) = counter.filter(|&index| index < block.len()) {
+                    checker.check_alias(counter, &block[counter], &declared);
+                }
+            }
+        }
+        checker.diagnostics
+    }
+    fn checker(&self) -> Checker<'_> {
+        let mut names: Vec<&String> = self.blocks.keys().collect();
+        names.sort();
+        let mut checker = Checker {
+            cpu: self,
+            globals: self.aliases.keys().cloned().collect(),
+            locals: HashMap::new(),
+            own: HashSet::new(),
+            diagnostics: vec![],
+            block: String::new(),
+        };
+        for name in names.iter() {
+            for (kind, alias) in declarations(&self.blocks[*name]) {
+                match kind {
+                    Instructions::GLOBAL => {
+                        checker.globals.insert(alias);
+                    }
+                    _ => {
+                        checker.locals.entry(alias).or_insert(name.to_string());
+                    }
+                }
+            }
+        }
+        checker
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The errors `check` reports for `src`.
+    fn errors(src: &str) -> Vec<String> {
+        let mut cpu = CPU::new();
+        cpu.init(10).unwrap();
+        cpu.parse_instructions(src.into())
+            .unwrap_or_else(|errors| panic!("{:?}", errors));
+        cpu.check()
+            .into_iter()
+            .filter(Diagnostic::is_error)
+            .map(|d| d.message)
+            .collect()
+    }
+
+    #[test]
+    fn read_only_aliases_belong_to_their_block() {
+        let src = "block main:\n\
+             \tdeclare ro r1, limit\n\
+             \trun other\n\
+             end\n\
+             block other:\n\
+             \tdeclare r2, limit\n\
+             \tmov limit, 5\n\
+             end\n";
+        assert!(errors(src).is_empty(), "{:?}", errors(src));
+        let src = "block main:\n\
+             \tdeclare ro r1, limit\n\
+             \tmov limit, 5\n\
+             end\n";
+        assert_eq!(errors(src), ["`limit` is read-only"]);
+    }
+
+    /// The warnings `check` reports for `src`.
+    fn warnings(src: &str) -> Vec<String> {
+        let mut cpu = CPU::new();
+        cpu.init(10).unwrap();
+        cpu.parse_instructions(src.into())
+            .unwrap_or_else(|errors| panic!("{:?}", errors));
+        cpu.check()
+            .into_iter()
+            .filter(|d| d.severity == Severity::Warning)
+            .map(|d| d.message)
+            .collect()
+    }
+
+    #[test]
+    fn reports_undeclared_and_misplaced_aliases() {
+        let src = "block main:\n\
+             \tmov count, 1\n\
+             \tdeclare r1, count\n\
+             \tmov total, 2\n\
+             \tmov other, 3\n\
+             \trun helper\n\
+             end\n\
+             block helper:\n\
+             \tdeclare r2, other\n\
+             end\n";
+        assert_eq!(
+            errors(src),
+            [
+                "alias `count` is used before it is declared",
+                "undeclared alias `total`",
+                "undeclared alias `other`, it is local to block `helper`",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_bad_registers_and_missing_blocks() {
+        let src = "block main:\nmov r12, 1\nrun nowhere\nend\n";
+        assert_eq!(
+            errors(src),
+            [
+                "register r12 is out of bounds, only 10 registers are available",
+                "`run nowhere` refers to a block that does not exist",
+            ]
+        );
+    }
+
+    #[test]
+    fn warns_about_unreachable_code_and_unused_blocks() {
+        let src = "block main:\nhalt 0\nprintln 1\nend\nblock unused:\nend\n";
+        assert_eq!(
+            warnings(src),
+            [
+                "unreachable code after `halt`",
+                "block `unused` is never run",
+            ]
+        );
+    }
+
+    #[test]
+    fn diagnostics_name_the_file_of_their_block() {
+        let path = std::env::temp_dir().join(format!("checker-{}.ben", std::process::id()));
+        std::fs::write(&path, "block main:\nmov nothing, 1\nend\n").unwrap();
+        let mut cpu = CPU::new();
+        cpu.init(10).unwrap();
+        let loaded = cpu.load_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        loaded.unwrap();
+        let diagnostics = cpu.check();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].file, Some(path.display().to_string()));
+    }
+}
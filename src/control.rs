@@ -0,0 +1,183 @@
+use crate::{
+    cpu::{FrameKind, CPU},
+    error::{Error, RuntimeError},
+    venobjects::VenObjects,
+};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Stops a running program from another thread. Clones share the same flag,
+/// so the host keeps one and hands the other to the thread running the CPU.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+    /// Clears a cancellation so the CPU can run again.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Where a program stands after `CPU::run_for` returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The instruction budget ran out; call `run_for` again to continue.
+    Running,
+    /// The script executed `yield`.
+    Yielded,
+    /// The program has run to completion.
+    Finished,
+}
+
+/// How a finished program ended.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExitStatus {
+    /// `acc` when the program stopped.
+    pub acc: VenObjects,
+    /// The code given to `halt`, if the program halted.
+    pub code: Option<i32>,
+}
+
+impl CPU {
+    /// Prepares the loaded program to run from the beginning of its entry
+    /// block, `main` unless `entry` says otherwise. A program without one is
+    /// an error rather than something that silently does nothing.
+    pub fn start(&mut self) -> Result<(), RuntimeError> {
+        let entry = self.entry.clone();
+        self.start_at(&entry)
+    }
+    fn start_at(&mut self, name: &str) -> Result<(), RuntimeError> {
+        let body = self.blocks.get(name).cloned().ok_or_else(|| {
+            Error::NO_ENTRY_POINT.with(format!("there is no block named `{}` to run", name))
+        })?;
+        self.unwind();
+        self.exit_code = None;
+        self.reset_budget();
+        self.enter(FrameKind::Entry, Some(name.to_string()), body);
+        Ok(())
+    }
+    /// Abandons whatever is running, for example after an error, leaving
+    /// registers and global aliases as they are.
+    pub fn unwind(&mut self) {
+        self.frames.clear();
+        while !self.scopes.is_empty() {
+            self.pop_scope();
+        }
+        self.call_depth = 0;
+    }
+    /// Runs a single block to completion, as a fresh run, with `args` written
+    /// to `r0`, `r1`, ... beforehand. The block's result is the `acc` of the
+    /// returned status.
+    pub fn run_block(
+        &mut self,
+        name: &str,
+        args: &[VenObjects],
+    ) -> Result<ExitStatus, RuntimeError> {
+        self.start_at(name)?;
+        for (rid, arg) in args.iter().enumerate() {
+            self.write_register(rid, arg.clone())?;
+        }
+        while self.run_frames(0, None)? != Status::Finished {}
+        Ok(self.exit_status())
+    }
+    /// Executes at most `n` instructions of the loaded program, starting it
+    /// on the first call and resuming where the previous call stopped after
+    /// that. Lets a host interleave several programs on one thread.
+    pub fn run_for(&mut self, n: u64) -> Result<Status, RuntimeError> {
+        if self.frames.is_empty() {
+            if self.started.is_some() {
+                return Ok(Status::Finished);
+            }
+            self.start()?;
+        }
+        let budget = self.executed.saturating_add(n);
+        self.run_frames(0, Some(budget))
+    }
+    pub fn exit_status(&self) -> ExitStatus {
+        ExitStatus {
+            acc: self.acc.clone(),
+            code: self.exit_code,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Duration};
+
+    fn load(src: &str) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.init(10).unwrap();
+        cpu.quiet = true;
+        cpu.parse_instructions(src.into())
+            .unwrap_or_else(|errors| panic!("{:?}", errors));
+        cpu
+    }
+
+    const COUNT: &str = "block main:\nmov r1, 0\ntimes 10\nadd r1, 1\nendtimes\nhalt 7\nend\n";
+
+    #[test]
+    fn run_for_continues_where_it_stopped() {
+        let mut cpu = load(COUNT);
+        let mut calls = 0;
+        while cpu.run_for(3).unwrap() == Status::Running {
+            calls += 1;
+            assert!(cpu.executed <= 3 * calls);
+        }
+        assert!(calls > 1);
+        assert_eq!(cpu.registers[1], VenObjects::Int(10));
+        assert_eq!(cpu.exit_status().code, Some(7));
+        assert_eq!(cpu.run_for(3).unwrap(), Status::Finished);
+    }
+
+    #[test]
+    fn run_for_stops_at_yield() {
+        let mut cpu = load("block main:\nmov r1, 1\nyield\nmov r1, 2\nend\n");
+        assert_eq!(cpu.run_for(100).unwrap(), Status::Yielded);
+        assert_eq!(cpu.registers[1], VenObjects::Int(1));
+        assert_eq!(cpu.run_for(100).unwrap(), Status::Finished);
+        assert_eq!(cpu.registers[1], VenObjects::Int(2));
+    }
+
+    #[test]
+    fn another_thread_can_cancel_a_run() {
+        let mut cpu =
+            load("block main:\nwhile true\nendwhile\nend\nblock done:\nmov acc, 1\nend\n");
+        let token = cpu.cancel.clone();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            token.cancel();
+        });
+        let err = cpu.exec(None).unwrap_err();
+        canceller.join().unwrap();
+        assert_eq!(err.kind, Error::CANCELLED);
+        // a cancelled CPU refuses to run until the token is reset
+        assert_eq!(
+            cpu.run_block("done", &[]).unwrap_err().kind,
+            Error::CANCELLED
+        );
+        cpu.cancel.reset();
+        assert_eq!(cpu.run_block("done", &[]).unwrap().acc, VenObjects::Int(1));
+    }
+
+    #[test]
+    fn run_block_passes_arguments_in_registers() {
+        let mut cpu = load("block sum:\nadd r0, r1\nmov acc, r0\nend\n");
+        let status = cpu
+            .run_block("sum", &[VenObjects::Int(2), VenObjects::Int(3)])
+            .unwrap();
+        assert_eq!(status.acc, VenObjects::Int(5));
+        assert_eq!(cpu.frames.len(), 0);
+        let err = cpu.run_block("missing", &[]).unwrap_err();
+        assert_eq!(err.kind, Error::NO_ENTRY_POINT);
+    }
+}
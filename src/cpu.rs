@@ -1,308 +1,790 @@
-#![allow(non_upper_case_globals, clippy::expect_fun_call)]
+#![allow(non_upper_case_globals)]
 
-use crate::{error::Error, insts::Instructions, venobjects::VenObjects};
-use std::{collections::HashMap, process::exit};
+use crate::{
+    control::{CancelToken, ExitStatus, Status},
+    diagnostic::Span,
+    disasm::disassemble_at,
+    error::{Error, RuntimeError},
+    insts::Instructions,
+    limits::Limits,
+    sandbox::SandboxPolicy,
+    venobjects::{VenObjects, VenType},
+};
+use std::{collections::HashMap, mem::size_of, sync::Arc, time::Instant};
 
-#[derive(Debug)]
+/// Upper bound on the size the register file may grow to.
+pub const MAX_REGISTERS: usize = 1 << 16;
+
+/// A `while`, `times` or `for` loop currently executing inside a frame.
+#[derive(Debug, Clone)]
+pub(crate) struct Loop {
+    /// Where the next iteration starts: the `while` token, which re-evaluates
+    /// its condition, or the first token of a `times`/`for` body.
+    pub(crate) start: usize,
+    /// Index of the matching `endwhile`/`endtimes`/`endfor`.
+    pub(crate) end: usize,
+    pub(crate) kind: LoopKind,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum LoopKind {
+    While,
+    /// `times N` or `times N as counter`.
+    Times {
+        remaining: i64,
+        index: i64,
+        counter: Option<usize>,
+    },
+    /// `for counter, start, end, step`; `end` is exclusive.
+    For {
+        counter: usize,
+        value: i64,
+        end: i64,
+        step: i64,
+    },
+}
+
+/// How a frame was entered, which decides what leaving it undoes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// The program's top level, which has no alias scope of its own.
+    Top,
+    /// The `main` block.
+    Entry,
+    /// A block entered through `run`, counted against the call depth.
+    Call,
+}
+
+/// A body being executed and where execution is inside it.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub kind: FrameKind,
+    /// The block being run, `None` for the top level.
+    pub block: Option<String>,
+    pub body: Arc<Vec<Instructions>>,
+    /// Index of the next token to execute.
+    pub pc: usize,
+    pub(crate) loops: Vec<Loop>,
+}
+
+/// Returns the index of the token closing the construct opened at `open`.
+/// With `stop_at_else` an `if` also stops at its own `else`.
+fn find_closing(tokens: &[Instructions], open: usize, stop_at_else: bool) -> usize {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(open + 1) {
+        match token {
+            Instructions::IF | Instructions::WHILE | Instructions::TIMES | Instructions::FOR => {
+                depth += 1
+            }
+            Instructions::ELSE if depth == 0 && stop_at_else => return i,
+            Instructions::ENDIF
+            | Instructions::ENDWHILE
+            | Instructions::ENDTIMES
+            | Instructions::ENDFOR => {
+                if depth == 0 {
+                    return i;
+                }
+                depth -= 1;
+            }
+            _ => {}
+        }
+    }
+    tokens.len()
+}
+
+#[derive(Debug, Clone)]
 pub struct CPU {
     pub registers: Vec<VenObjects>,
     pub acc: VenObjects,
-    pub blocks: HashMap<String, Vec<Instructions>>,
+    pub blocks: HashMap<String, Arc<Vec<Instructions>>>,
+    /// Aliases visible from every block: those declared with `global`.
     pub aliases: HashMap<String, usize>,
+    /// One scope per running block, holding the aliases it `declare`d.
+    pub scopes: Vec<HashMap<String, usize>>,
+    /// Registers declared with a type, e.g. `declare r1, count: int`, with
+    /// the alias they were declared under. Like `read_only`, entries made
+    /// through a block's alias go away when the block returns.
+    pub register_types: HashMap<usize, (String, VenType)>,
+    /// Registers declared with `declare ro`, with their alias.
+    pub read_only: HashMap<usize, String>,
     pub tokens: Vec<Instructions>,
+    /// Source position of every instruction of each block in `blocks`.
+    pub spans: HashMap<String, Vec<Span>>,
+    /// `///` documentation of the blocks that have any.
+    pub docs: HashMap<String, String>,
+    /// The file each block was loaded from, for blocks loaded from a file.
+    pub files: HashMap<String, String>,
+    /// Symbols visible to `#if` and substituted by the preprocessor, either
+    /// defined on the command line or through `#define`.
+    pub defines: HashMap<String, String>,
+    /// Set by `#strict!`: reading an empty register is an error instead of
+    /// silently yielding `None`.
+    pub strict: bool,
+    pub call_depth: usize,
+    /// Set by `#grow` or `--grow-registers`: writing past the last register
+    /// grows the register file, up to `MAX_REGISTERS`, instead of failing.
+    pub grow_registers: bool,
+    pub limits: Limits,
+    /// Instructions executed since the run started.
+    pub executed: u64,
+    pub started: Option<Instant>,
+    /// Bytes owned by the values in `registers` and `acc`.
+    pub heap_bytes: usize,
+    pub sandbox: SandboxPolicy,
+    /// The call stack, innermost frame last.
+    pub frames: Vec<Frame>,
+    pub cancel: CancelToken,
+    /// Set by `halt`.
+    pub exit_code: Option<i32>,
+    /// The block a run starts from.
+    pub entry: String,
+    /// Arguments passed to the program, read with `argc` and `argv`.
+    pub args: Vec<String>,
+    /// Print every instruction to stderr before executing it.
+    pub trace: bool,
+    /// Discard program output; it is still counted against the sandbox cap.
+    pub quiet: bool,
+    /// Bytes printed since the run started.
+    pub output_bytes: usize,
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CPU {
-    pub fn init(&mut self, no_of_regs: usize) {
-        for _ in 0..no_of_regs {
-            self.registers.push(VenObjects::Empty);
+    pub fn new() -> Self {
+        CPU {
+            registers: vec![],
+            acc: VenObjects::Empty,
+            blocks: HashMap::new(),
+            aliases: HashMap::new(),
+            scopes: vec![],
+            register_types: HashMap::new(),
+            read_only: HashMap::new(),
+            tokens: vec![],
+            spans: HashMap::new(),
+            docs: HashMap::new(),
+            files: HashMap::new(),
+            defines: HashMap::new(),
+            strict: false,
+            call_depth: 0,
+            grow_registers: false,
+            limits: Limits::default(),
+            executed: 0,
+            started: None,
+            heap_bytes: 0,
+            sandbox: SandboxPolicy::default(),
+            frames: vec![],
+            cancel: CancelToken::default(),
+            exit_code: None,
+            entry: "main".to_string(),
+            args: vec![],
+            trace: false,
+            quiet: false,
+            output_bytes: 0,
+        }
+    }
+    /// Sets the register file to `no_of_regs` registers. Registers that
+    /// remain keep their values, new ones start out empty. Fails without
+    /// resizing above `MAX_REGISTERS` or when the new registers would not fit
+    /// in `limits.max_memory`.
+    pub fn init(&mut self, no_of_regs: usize) -> Result<(), RuntimeError> {
+        if no_of_regs > MAX_REGISTERS {
+            return Err(Error::INVALID_REGISTER_OR_VALUE.with(format!(
+                "{} registers requested, at most {} are supported",
+                no_of_regs, MAX_REGISTERS
+            )));
+        }
+        if let Some(max) = self.limits.max_memory {
+            let needed = (no_of_regs + 1) * size_of::<VenObjects>() + self.heap_bytes;
+            if no_of_regs > self.registers.len() && needed > max {
+                return Err(Error::MEMORY_LIMIT.with(format!(
+                    "{} registers need {} bytes, the limit is {}",
+                    no_of_regs, needed, max
+                )));
+            }
         }
+        self.registers.resize(no_of_regs, VenObjects::Empty);
+        self.heap_bytes = self
+            .registers
+            .iter()
+            .chain([&self.acc])
+            .map(VenObjects::heap_size)
+            .sum();
+        Ok(())
     }
-    pub fn exec(&mut self, tokens: Option<&Vec<Instructions>>) {
-        let insts;
-        match tokens {
-            Some(tok) => insts = tok,
+    /// Runs `tokens`, or the loaded program's entry block when `None` is
+    /// given, until it finishes or halts, and stops at the first runtime
+    /// error or exceeded limit.
+    pub fn exec(&mut self, tokens: Option<&Vec<Instructions>>) -> Result<ExitStatus, RuntimeError> {
+        let base = match tokens {
+            Some(tokens) => {
+                let base = self.frames.len();
+                self.enter(FrameKind::Top, None, Arc::new(tokens.clone()));
+                base
+            }
             None => {
-                let temp = self.tokens.clone();
-                self.exec(Some(&temp));
-                return;
+                self.start()?;
+                0
             }
+        };
+        while self.run_frames(base, None)? != Status::Finished {}
+        Ok(self.exit_status())
+    }
+    /// Pushes a frame running `body`, with a fresh alias scope unless it is
+    /// the top level.
+    pub(crate) fn enter(
+        &mut self,
+        kind: FrameKind,
+        block: Option<String>,
+        body: Arc<Vec<Instructions>>,
+    ) {
+        if kind != FrameKind::Top {
+            self.scopes.push(HashMap::new());
         }
-        let tokens = insts;
-        let mut i = 0;
-        while i < tokens.len() {
-            match tokens[i].clone() {
-                Instructions::BLOCK(name, insts) => {
-                    let mut insts = insts;
-                    insts.remove(0);
-                    if name == "main" {
-                        self.exec(Some(&insts));
-                        exit(0);
-                    }
-                    i+=1;
+        if kind == FrameKind::Call {
+            self.call_depth += 1;
+        }
+        self.frames.push(Frame {
+            kind,
+            block,
+            body,
+            pc: 0,
+            loops: vec![],
+        });
+    }
+    fn leave(&mut self) {
+        let Some(frame) = self.frames.pop() else {
+            return;
+        };
+        if frame.kind != FrameKind::Top {
+            self.pop_scope();
+        }
+        if frame.kind == FrameKind::Call {
+            self.call_depth -= 1;
+        }
+    }
+    /// Drops the innermost alias scope together with the type and read-only
+    /// constraints its aliases put on their registers.
+    pub(crate) fn pop_scope(&mut self) {
+        let Some(scope) = self.scopes.pop() else {
+            return;
+        };
+        for (alias, rid) in scope {
+            if self.read_only.get(&rid) == Some(&alias) {
+                self.read_only.remove(&rid);
+            }
+            if self
+                .register_types
+                .get(&rid)
+                .is_some_and(|(declared, _)| *declared == alias)
+            {
+                self.register_types.remove(&rid);
+            }
+        }
+    }
+    /// Executes frames above `base` until they have all returned, the script
+    /// yields, or `budget` instructions in total have been executed.
+    pub(crate) fn run_frames(
+        &mut self,
+        base: usize,
+        budget: Option<u64>,
+    ) -> Result<Status, RuntimeError> {
+        while self.frames.len() > base {
+            if self.cancel.is_cancelled() {
+                return Err(Error::CANCELLED.with("execution was cancelled by the host"));
+            }
+            if budget.is_some_and(|budget| self.executed >= budget) {
+                return Ok(Status::Running);
+            }
+            if self.step()? {
+                return Ok(Status::Yielded);
+            }
+        }
+        Ok(Status::Finished)
+    }
+    /// Executes the next token of the innermost frame and returns whether it
+    /// was a `yield`.
+    fn step(&mut self) -> Result<bool, RuntimeError> {
+        let depth = self.frames.len() - 1;
+        let frame = &mut self.frames[depth];
+        let tokens = Arc::clone(&frame.body);
+        let i = frame.pc;
+        if i >= tokens.len() {
+            self.leave();
+            return Ok(false);
+        }
+        if self.trace && tokens[i].descriptor().is_some() {
+            let block = frame.block.as_deref().unwrap_or("<top>");
+            eprintln!("[{}] {}", block, disassemble_at(&tokens, i).0);
+        }
+        let mut loops = std::mem::take(&mut frame.loops);
+        let result = self.execute(&tokens, i, &mut loops);
+        // a `run` has pushed a new frame on top, so this one is found by depth
+        if let Some(frame) = self.frames.get_mut(depth) {
+            frame.loops = loops;
+            if let Ok(next) = result {
+                frame.pc = next;
+            }
+        }
+        result.map(|_| tokens[i] == Instructions::YIELD)
+    }
+    /// Executes the instruction at `i` and returns where execution continues.
+    fn execute(
+        &mut self,
+        tokens: &[Instructions],
+        i: usize,
+        loops: &mut Vec<Loop>,
+    ) -> Result<usize, RuntimeError> {
+        // Where execution continues unless the instruction jumps.
+        let mut next = i + tokens[i].descriptor().map_or(1, |d| 1 + d.arity());
+        if let Some(descriptor) = tokens[i].descriptor() {
+            // bodies built by hand or restored from a snapshot are not
+            // validated by the parser, so operands may be missing
+            let last = match tokens.get(i + 2) {
+                Some(Instructions::AS) if tokens[i] == Instructions::TIMES => i + 3,
+                _ => next - 1,
+            };
+            if last >= tokens.len() {
+                return Err(Error::INVALID_REGISTER_OR_VALUE
+                    .with(format!("`{}` is missing operands", descriptor.mnemonic)));
+            }
+            self.tick()?;
+            self.enforce(&tokens[i])?;
+        }
+        match &tokens[i] {
+            Instructions::BLOCK(name, insts) if *name == self.entry => {
+                let body = self
+                    .blocks
+                    .get(name)
+                    .cloned()
+                    .unwrap_or_else(|| Arc::new(insts.clone()));
+                self.enter(FrameKind::Entry, Some(name.clone()), body);
+            }
+            Instructions::DECLARE | Instructions::DECLARE_RO | Instructions::GLOBAL => {
+                if tokens[i + 1] == Instructions::ACC {
+                    return Err(Error::CANNOT_DECLARE_ACC
+                        .with("Cannot declare acc, acc is a special value"));
                 }
-                Instructions::DECLARE => {
-                    if tokens[i + 1] == Instructions::ACC {
-                        Error::throw(
-                            Error::CANNOT_DECLARE_ACC,
-                            Some("Cannot declare acc, acc is a special value"),
-                        );
+                let reg = self.get_reg_id(&tokens[i + 1]).ok_or_else(|| {
+                    Error::INVALID_REGISTER_OR_VALUE.with(tokens[i + 1].to_string())
+                })?;
+                let Instructions::IDENT(alias) = &tokens[i + 2] else {
+                    return Err(Error::INVALID_DECLARATION.with(format!("{:?}", tokens[i + 2])));
+                };
+                match self.scopes.last_mut() {
+                    Some(scope) if tokens[i] != Instructions::GLOBAL => {
+                        scope.insert(alias.clone(), reg);
                     }
-                    let reg = self
-                        .get_reg_id(&tokens[i + 1])
-                        .expect(&Error::INVALID_REGISTER_OR_VALUE.extract());
-                    let alias = &tokens[i + 2]
-                        .extract_value()
-                        .expect(&Error::INVALID_DECLARATION.extract());
-                    self.aliases.insert(alias.get_str(), reg);
-                    i += 3;
-                }
-                Instructions::MOV => {
-                    let val = tokens[i + 2].clone();
-                    let val = val.get_val_or_reg_val(&self.registers, &self.acc, &self.aliases);
-
-                    match tokens[i + 1].clone() {
-                        Instructions::REG(id) => self.registers[id] = val,
-                        Instructions::ACC => self.acc = val,
-                        Instructions::DATA(VenObjects::Str(alias)) => {
-                            if let Some(&reg_id) = self.aliases.get(&alias) {
-                                self.registers[reg_id] = val;
-                            } else {
-                                Error::throw(
-                                    Error::INVALID_VALUE_FOR_MOVE,
-                                    Some(
-                                        format!("{:?}", Instructions::DATA(VenObjects::Str(alias)))
-                                            .as_str(),
-                                    ),
-                                );
-                            }
-                        }
-                        a => Error::throw(
-                            Error::INVALID_VALUE_FOR_MOVE,
-                            Some(format!("{:?}", a).as_str()),
-                        ),
+                    _ => {
+                        self.aliases.insert(alias.clone(), reg);
                     }
-                    i += 3;
                 }
-                Instructions::AND => {
-                    self.operate_bool(i, &tokens, |x, y| x && y);
-                    i += 3;
-                }
-                Instructions::OR => {
-                    self.operate_bool(i, &tokens, |x, y| x || y);
-                    i += 3;
-                }
-                Instructions::XOR => {
-                    self.operate_bool(i, &tokens, |x, y| x ^ y);
-                    i += 3;
-                }
-                Instructions::ROOT => {
-                    self.operate_int(i, &tokens, |x, y| x.powf(1.0 / y));
-                    i += 3;
-                }
-                Instructions::ADD => {
-                    self.operate_int(i, &tokens, |x, y| x + y);
-                    i += 3;
-                }
-                Instructions::SUB => {
-                    self.operate_int(i, &tokens, |x, y| x - y);
-                    i += 3;
-                }
-                Instructions::DIV => {
-                    self.operate_int(i, &tokens, |x, y| x / y);
-                    i += 3;
-                }
-                Instructions::MUL => {
-                    self.operate_int(i, &tokens, |x, y| x * y);
-                    i += 3;
-                }
-                Instructions::POW => {
-                    self.operate_int(i, &tokens, |x, y| x.powf(y));
-                    i += 3;
-                }
-                Instructions::LT => {
-                    self.operate_int_to_bool(i, &tokens, |x, y| x < y);
-                    i += 3;
+                if let Some(Instructions::TYPE(ven_type)) = tokens.get(i + 3) {
+                    next += 1;
+                    self.register_types.insert(reg, (alias.clone(), *ven_type));
+                    let current = self.read_register(reg)?;
+                    if current != VenObjects::Empty {
+                        self.check_type(reg, &current)?;
+                    }
                 }
-                Instructions::GT => {
-                    self.operate_int_to_bool(i, &tokens, |x, y| x > y);
-                    i += 3;
+                if tokens[i] == Instructions::DECLARE_RO {
+                    self.read_only.insert(reg, alias.clone());
                 }
-                Instructions::EQ => {
-                    self.operate_int_to_bool(i, &tokens, |x, y| x == y);
-                    i += 3;
+            }
+            Instructions::MOV => {
+                let val = self.get_reg(&tokens[i + 2])?;
+                self.store(&tokens[i + 1], val)?;
+            }
+            Instructions::ARGC => {
+                let count = VenObjects::Int(self.args.len() as i64);
+                self.store(&tokens[i + 1], count)?;
+            }
+            Instructions::ARGV => {
+                let index = self.get_reg(&tokens[i + 2])?;
+                let index = index.get_int().ok_or_else(|| {
+                    Error::INVALID_INT_OPERAND
+                        .with(format!("`argv` needs an int index, got {:?}", index))
+                })?;
+                let arg = usize::try_from(index)
+                    .ok()
+                    .and_then(|index| self.args.get(index))
+                    .map_or(VenObjects::Empty, |arg| VenObjects::Str(arg.clone()));
+                self.store(&tokens[i + 1], arg)?;
+            }
+            Instructions::GETENV => {
+                let VenObjects::Str(name) = self.get_reg(&tokens[i + 2])? else {
+                    return Err(Error::INVALID_REGISTER_OR_VALUE
+                        .with("`getenv` needs the variable name as a string"));
+                };
+                let value = std::env::var(&name).map_or(VenObjects::Empty, VenObjects::Str);
+                self.store(&tokens[i + 1], value)?;
+            }
+            Instructions::AND => {
+                self.operate_bool(i, tokens, |x, y| x && y)?;
+            }
+            Instructions::OR => {
+                self.operate_bool(i, tokens, |x, y| x || y)?;
+            }
+            Instructions::XOR => {
+                self.operate_bool(i, tokens, |x, y| x ^ y)?;
+            }
+            Instructions::ROOT => {
+                self.operate_int(i, tokens, |x, y| x.powf(1.0 / y))?;
+            }
+            Instructions::ADD => {
+                self.operate_int(i, tokens, |x, y| x + y)?;
+            }
+            Instructions::SUB => {
+                self.operate_int(i, tokens, |x, y| x - y)?;
+            }
+            Instructions::DIV => {
+                self.operate_int(i, tokens, |x, y| x / y)?;
+            }
+            Instructions::MUL => {
+                self.operate_int(i, tokens, |x, y| x * y)?;
+            }
+            Instructions::POW => {
+                self.operate_int(i, tokens, |x, y| x.powf(y))?;
+            }
+            Instructions::LT => {
+                self.operate_int_to_bool(i, tokens, |x, y| x < y)?;
+            }
+            Instructions::GT => {
+                self.operate_int_to_bool(i, tokens, |x, y| x > y)?;
+            }
+            Instructions::EQ => {
+                self.operate_int_to_bool(i, tokens, |x, y| x == y)?;
+            }
+            Instructions::NOT => {
+                let cond = self.get_bool(&tokens[i + 1])?;
+                self.acc = VenObjects::Bool(!cond);
+                if let Some(bool_id) = self.get_reg_id(&tokens[i + 1]) {
+                    self.write_register(bool_id, self.acc.clone())?;
                 }
-                Instructions::NOT => {
-                    let bool_cond =
-                        tokens[i + 1].get_val_or_reg_val(&self.registers, &self.acc, &self.aliases);
-                    self.acc = VenObjects::Bool(
-                        !bool_cond
-                            .get_bool()
-                            .expect(&Error::INVALID_BOOL_OPERAND.extract()),
+            }
+
+            Instructions::PRINT => {
+                let text = self.get_reg(&tokens[i + 1])?.to_string();
+                self.emit(&text)?;
+            }
+            Instructions::PRINTLN => {
+                let text = format!("{}\n", self.get_reg(&tokens[i + 1])?);
+                self.emit(&text)?;
+            }
+            Instructions::RUN => {
+                let Instructions::IDENT(block_name) = &tokens[i + 1] else {
+                    return Err(
+                        Error::INVALID_RUN_BLOCK_SYNTAX.with(format!("{:?}", tokens[i + 1]))
                     );
-                    let bool_id = self.get_reg_id(&tokens[i + 1]);
-                    match bool_id {
-                        Some(bool_id) => self.registers[bool_id] = self.acc.clone(),
-                        None => {}
+                };
+                if let Some(max) = self.limits.max_call_depth {
+                    if self.call_depth >= max {
+                        return Err(Error::STACK_OVERFLOW.with(format!(
+                            "`run {}` exceeds a call depth of {}",
+                            block_name, max
+                        )));
                     }
-                    i += 2;
                 }
-
-                Instructions::PRINT => {
-                    let text =
-                        tokens[i + 1].get_val_or_reg_val(&self.registers, &self.acc, &self.aliases);
-                    match text {
-                        VenObjects::Int(num) => print!("{}", num),
-                        VenObjects::Str(stri) => print!("{}", stri),
-                        VenObjects::Float(float) => print!("{}", float),
-                        VenObjects::Bool(bol) => print!("{}", bol),
-                        VenObjects::Class(name, insts) => print!("{}: {:?}", name, insts),
-                        VenObjects::Function(name, body) => print!("{}: {:?}", name, body),
-                        VenObjects::Empty => print!("None")
-                    }
-                    i += 2;
+                let body = self.blocks.get(block_name).cloned().ok_or_else(|| {
+                    Error::INVALID_RUN_BLOCK_SYNTAX.with(format!("no block named `{}`", block_name))
+                })?;
+                self.enter(FrameKind::Call, Some(block_name.clone()), body);
+            }
+            Instructions::IF => {
+                let condition = self.get_bool(&tokens[i + 1])?;
+                if !condition {
+                    next = find_closing(tokens, i, true) + 1;
                 }
-                Instructions::PRINTLN => {
-                    let text =
-                        tokens[i + 1].get_val_or_reg_val(&self.registers, &self.acc, &self.aliases);
-                    match text {
-                        VenObjects::Int(num) => println!("{}", num),
-                        VenObjects::Str(stri) => println!("{}", stri),
-                        VenObjects::Float(float) => println!("{}", float),
-                        VenObjects::Bool(bol) => println!("{}", bol),
-                        VenObjects::Class(name, insts) => println!("{}: {:?}", name, insts),
-                        VenObjects::Function(name, body) => println!("{}: {:?}", name, body),
-                        VenObjects::Empty => print!("None")
+            }
+            Instructions::ELSE => {
+                // Only reached by falling off the end of the `if` branch.
+                next = find_closing(tokens, i, false) + 1;
+            }
+            Instructions::WHILE => {
+                let condition = self.get_bool(&tokens[i + 1])?;
+                // a `times`/`for` body starting with this `while` has the
+                // same `start`, so the kind has to match too
+                let is_current = loops
+                    .last()
+                    .is_some_and(|l| matches!(l.kind, LoopKind::While) && l.start == i);
+                if condition {
+                    if !is_current {
+                        loops.push(Loop {
+                            start: i,
+                            end: find_closing(tokens, i, false),
+                            kind: LoopKind::While,
+                        });
                     }
-                    i += 2;
+                } else if is_current {
+                    next = loops.pop().map_or(tokens.len(), |l| l.end + 1);
+                } else {
+                    next = find_closing(tokens, i, false) + 1;
                 }
-                Instructions::RUN => {
-                    let block_name = &tokens[i + 1];
-                    match block_name {
-                        Instructions::DATA(block_name) => {
-                            let block_name = block_name.get_str();
-                            self.exec(Some(
-                                &self
-                                    .blocks
-                                    .get(&block_name)
-                                    .expect("Error: invalid run block command")
-                                    .clone(),
-                            ));
-                        }
-                        a => Error::throw(
-                            Error::INVALID_RUN_BLOCK_SYNTAX,
-                            Some(format!("{:?}", a).as_str()),
-                        ),
+            }
+            Instructions::TIMES => {
+                let times = self.get_reg(&tokens[i + 1])?;
+                let times = times
+                    .get_int()
+                    .ok_or_else(|| Error::INVALID_TIMES_LOOP_SYNTAX.with(format!("{:?}", times)))?;
+                let (counter, body) = if tokens.get(i + 2) == Some(&Instructions::AS) {
+                    (Some(self.get_counter_id(&tokens[i + 3])?), i + 4)
+                } else {
+                    (None, i + 2)
+                };
+                let end = find_closing(tokens, i, false);
+                if times > 0 {
+                    if let Some(counter) = counter {
+                        self.write_register(counter, VenObjects::Int(0))?;
                     }
-                    i += 2;
+                    loops.push(Loop {
+                        start: body,
+                        end,
+                        kind: LoopKind::Times {
+                            remaining: times,
+                            index: 0,
+                            counter,
+                        },
+                    });
+                    next = body;
+                } else {
+                    next = end + 1;
                 }
-                Instructions::IF => {
-                    let condition = &tokens[i + 1].get_val_or_reg_val(
-                        &self.registers,
-                        &self.acc,
-                        &self.aliases,
-                    );
-                    let mut inst = vec![];
-                    let mut is_else = vec![];
-
-                    for i in tokens.iter().skip(i + 1).enumerate() {
-                        if *i.1 == Instructions::EOL {
-                            if tokens[i.0 + 1] == Instructions::ELSE {
-                                for i in tokens.iter().skip(i.0 + 1) {
-                                    if *i == Instructions::EOL {
-                                        break;
-                                    }
-                                    is_else.push(i);
+            }
+            Instructions::FOR => {
+                let counter = self.get_counter_id(&tokens[i + 1])?;
+                let mut bounds = [0; 3];
+                for (bound, offset) in bounds.iter_mut().zip(2..) {
+                    let value = self.get_reg(&tokens[i + offset])?;
+                    *bound = value.get_int().ok_or_else(|| {
+                        Error::INVALID_FOR_LOOP_SYNTAX.with(format!("{:?}", value))
+                    })?;
+                }
+                let [start, end, step] = bounds;
+                if step == 0 {
+                    return Err(Error::INVALID_FOR_LOOP_SYNTAX.with("`for` step cannot be 0"));
+                }
+                let closing = find_closing(tokens, i, false);
+                if (step > 0 && start < end) || (step < 0 && start > end) {
+                    self.write_register(counter, VenObjects::Int(start))?;
+                    loops.push(Loop {
+                        start: i + 5,
+                        end: closing,
+                        kind: LoopKind::For {
+                            counter,
+                            value: start,
+                            end,
+                            step,
+                        },
+                    });
+                } else {
+                    next = closing + 1;
+                }
+            }
+            Instructions::ENDWHILE => {
+                next = Self::innermost(loops, "endwhile")?.start;
+            }
+            Instructions::ENDTIMES | Instructions::ENDFOR => {
+                let current = Self::innermost(loops, "loop end")?;
+                let again = match &mut current.kind {
+                    LoopKind::Times {
+                        remaining,
+                        index,
+                        counter,
+                    } => {
+                        *remaining -= 1;
+                        *index += 1;
+                        (*remaining > 0).then_some((*counter, *index))
+                    }
+                    LoopKind::For {
+                        counter,
+                        value,
+                        end,
+                        step,
+                    } => {
+                        // a step past the integer range ends the loop
+                        let more = match value.checked_add(*step) {
+                            Some(stepped) => {
+                                *value = stepped;
+                                if *step > 0 {
+                                    stepped < *end
+                                } else {
+                                    stepped > *end
                                 }
                             }
-                            break;
-                        } else {
-                            inst.push(i.1.clone());
-                        }
+                            None => false,
+                        };
+                        more.then_some((Some(*counter), *value))
                     }
-                    if condition
-                        .get_bool()
-                        .expect(&Error::INVALID_BOOL_OPERAND.extract())
-                    {
-                        self.exec(Some(&inst));
-                    } else if !is_else.is_empty() {
-                        self.exec(Some(&inst));
-                        i += 4;
-                    }
-                    i += 2 + 3;
-                }
-                Instructions::TIMES => {
-                    let mut insts = vec![];
-                    let times = tokens[i + 1]
-                        .get_val_or_reg_val(&self.registers, &self.acc, &self.aliases)
-                        .get_int()
-                        .unwrap_or_else(|| panic!("{}", Error::INVALID_RUN_BLOCK_SYNTAX.extract()));
-                    for i in tokens.iter().skip(i + 2) {
-                        if *i == Instructions::EOL {
-                            break;
-                        } else {
-                            insts.push(i.clone());
+                    LoopKind::While => None,
+                };
+                match again {
+                    Some((counter, value)) => {
+                        next = current.start;
+                        if let Some(counter) = counter {
+                            self.write_register(counter, VenObjects::Int(value))?;
                         }
                     }
-                    let mut id = 0;
-                    while times > id {
-                        self.exec(Some(&insts));
-                        id += 1;
+                    None => {
+                        loops.pop();
                     }
-                    i += insts.len() + 3;
-                }
-                Instructions::REG(_) => {
-                    i += 1;
-                }
-                Instructions::ACC => {
-                    i += 1;
-                }
-                Instructions::EOL => {
-                    i += 1;
-                }
-                Instructions::DATA(_) => {
-                    i += 1;
                 }
-                Instructions::END => {i+=1; return;}
-                _ => {
-                    i += 1;
+            }
+            Instructions::BREAK => {
+                next = Self::innermost(loops, "break")?.end + 1;
+                loops.pop();
+            }
+            Instructions::CONTINUE => {
+                next = Self::innermost(loops, "continue")?.end;
+            }
+            Instructions::HALT => {
+                let value = self.get_reg(&tokens[i + 1])?;
+                let code = value
+                    .get_int()
+                    .and_then(|code| i32::try_from(code).ok())
+                    .ok_or_else(|| {
+                        Error::INVALID_INT_OPERAND
+                            .with(format!("`halt` needs an exit code, got {:?}", value))
+                    })?;
+                self.exit_code = Some(code);
+                while !self.frames.is_empty() {
+                    self.leave();
                 }
             }
+            Instructions::END => next = tokens.len(),
+            _ => {}
         }
+        Ok(next)
+    }
+    /// The loop a loop-control instruction applies to.
+    fn innermost<'a>(loops: &'a mut [Loop], what: &str) -> Result<&'a mut Loop, RuntimeError> {
+        loops.last_mut().ok_or_else(|| {
+            Error::INVALID_BLOCK_SYNTAX.with(format!("`{}` outside of a loop", what))
+        })
     }
-    fn get_reg(&self, token: &Instructions) -> VenObjects {
+    fn get_reg(&self, token: &Instructions) -> Result<VenObjects, RuntimeError> {
         match token {
-            Instructions::REG(rid) => self.registers[*rid].clone(),
-            Instructions::ACC => self.acc.clone(),
-            Instructions::DATA(VenObjects::Str(alias)) => {
-                if let Some(&rid) = self.aliases.get(alias.as_str()) {
-                    self.registers[rid].clone()
-                } else {
-                    VenObjects::Str(alias.to_string())
-                }
-            }
-            Instructions::TRUE => VenObjects::Bool(true),
-            Instructions::FALSE => VenObjects::Bool(false),
-            Instructions::DATA(reg) => reg.clone(),
-            a => {
-                Error::throw(
-                    Error::INVALID_REGISTER_OR_VALUE,
-                    Some(format!("{:?}", a).as_str()),
-                );
-                VenObjects::Int(0)
+            Instructions::REG(rid) => self.read_register(*rid),
+            Instructions::ACC => Ok(self.acc.clone()),
+            Instructions::IDENT(alias) => match self.resolve_alias(alias) {
+                Some(rid) => self.read_register(rid),
+                None => Err(Error::UNDECLARED_ALIAS.with(alias.as_str())),
+            },
+            Instructions::TRUE => Ok(VenObjects::Bool(true)),
+            Instructions::FALSE => Ok(VenObjects::Bool(false)),
+            Instructions::DATA(reg) => Ok(reg.clone()),
+            a => Err(Error::INVALID_REGISTER_OR_VALUE.with(format!("{:?}", a))),
+        }
+    }
+    fn get_bool(&self, token: &Instructions) -> Result<bool, RuntimeError> {
+        let value = self.get_reg(token)?;
+        value
+            .get_bool()
+            .ok_or_else(|| Error::INVALID_BOOL_OPERAND.with(format!("{:?}", value)))
+    }
+    /// Looks `alias` up in the scope of the running block, then among the
+    /// globals.
+    fn resolve_alias(&self, alias: &str) -> Option<usize> {
+        self.scopes
+            .last()
+            .and_then(|scope| scope.get(alias))
+            .or_else(|| self.aliases.get(alias))
+            .copied()
+    }
+    fn read_register(&self, rid: usize) -> Result<VenObjects, RuntimeError> {
+        let val = match self.registers.get(rid) {
+            Some(val) => val.clone(),
+            None if self.grow_registers && rid < MAX_REGISTERS => VenObjects::Empty,
+            None => return Err(self.out_of_range(rid)),
+        };
+        if self.strict && val == VenObjects::Empty {
+            return Err(Error::UNINITIALIZED_REGISTER.with(format!("r{}", rid)));
+        }
+        Ok(val)
+    }
+    pub(crate) fn write_register(
+        &mut self,
+        rid: usize,
+        val: VenObjects,
+    ) -> Result<(), RuntimeError> {
+        if let Some(alias) = self.read_only.get(&rid) {
+            return Err(
+                Error::READ_ONLY_REGISTER.with(format!("`{}` (r{}) is read-only", alias, rid))
+            );
+        }
+        self.check_type(rid, &val)?;
+        if rid >= self.registers.len() {
+            if !self.grow_registers || rid >= MAX_REGISTERS {
+                return Err(self.out_of_range(rid));
             }
+            self.init(rid + 1)?;
+        }
+        let old = std::mem::replace(&mut self.registers[rid], val);
+        let new = self.registers[rid].clone();
+        self.account(&old, &new)
+    }
+    /// Fails if `rid` was declared with a type that `val` does not have.
+    fn check_type(&self, rid: usize, val: &VenObjects) -> Result<(), RuntimeError> {
+        let Some((alias, expected)) = self.register_types.get(&rid) else {
+            return Ok(());
+        };
+        if val.ven_type() == Some(*expected) {
+            return Ok(());
+        }
+        let found = val
+            .ven_type()
+            .map_or("none".to_string(), |found| found.to_string());
+        Err(Error::TYPE_MISMATCH.with(format!(
+            "`{}` (r{}) is declared {} but was assigned a {} value",
+            alias, rid, expected, found
+        )))
+    }
+    fn out_of_range(&self, rid: usize) -> RuntimeError {
+        Error::INVALID_REGISTER_OR_VALUE.with(format!(
+            "r{} is out of range, the register file has {} registers",
+            rid,
+            self.registers.len()
+        ))
+    }
+    /// Resolves the register a `times ... as` or `for` loop writes its
+    /// counter into.
+    fn get_counter_id(&self, token: &Instructions) -> Result<usize, RuntimeError> {
+        self.get_reg_id(token).ok_or_else(|| {
+            Error::INVALID_REGISTER_OR_VALUE.with(format!(
+                "loop counter must be a register or alias: {:?}",
+                token
+            ))
+        })
+    }
+    /// Writes `val` to the destination operand of `mov` and friends, which
+    /// may also be `acc`.
+    fn store(&mut self, dest: &Instructions, val: VenObjects) -> Result<(), RuntimeError> {
+        if *dest == Instructions::ACC {
+            let old = std::mem::replace(&mut self.acc, val);
+            let new = self.acc.clone();
+            return self.account(&old, &new);
+        }
+        let rid = self.get_dest_id(dest)?;
+        self.write_register(rid, val)
+    }
+    /// Resolves the register `mov` writes into.
+    fn get_dest_id(&self, token: &Instructions) -> Result<usize, RuntimeError> {
+        match token {
+            Instructions::IDENT(alias) => self
+                .resolve_alias(alias)
+                .ok_or_else(|| Error::UNDECLARED_ALIAS.with(alias.as_str())),
+            token => self
+                .get_reg_id(token)
+                .ok_or_else(|| Error::INVALID_VALUE_FOR_MOVE.with(format!("{:?}", token))),
         }
     }
     fn get_reg_id(&self, token: &Instructions) -> Option<usize> {
         match token {
             Instructions::REG(num) => Some(*num),
-            Instructions::DATA(VenObjects::Str(alias)) => {
-                if let Some(&rid) = self.aliases.get(alias) {
-                    Some(rid)
-                } else {
-                    None
-                }
-            }
+            Instructions::IDENT(alias) => self.resolve_alias(alias),
             _ => None,
         }
     }
@@ -311,73 +793,196 @@ impl CPU {
         i: usize,
         tokens: &[Instructions],
         f: F,
-    ) {
-        let to = tokens[i + 1].get_val_or_reg_val(&self.registers, &self.acc, &self.aliases);
-        let from = tokens[i + 2].get_val_or_reg_val(&self.registers, &self.acc, &self.aliases);
-        let mut can_be_float = false;
-        if to.get_int() == None || from.get_int() == None {
-            can_be_float = true;
+    ) -> Result<(), RuntimeError> {
+        let to = self.get_reg(&tokens[i + 1])?;
+        let from = self.get_reg(&tokens[i + 2])?;
+        match (to.as_float(), from.as_float()) {
+            (Some(to), Some(from)) => {
+                self.acc = VenObjects::Bool(f(to, from));
+                Ok(())
+            }
+            _ => Err(Error::INVALID_INT_OPERAND.with(format!("{:?}, {:?}", to, from))),
         }
-        if !can_be_float {
-            let to = to.get_int().unwrap();
-            let from = from.get_int().unwrap();
+    }
 
-            self.acc = VenObjects::Bool(f(to as f64, from as f64));
-        }
-        if can_be_float {
-            let to = to.get_float().expect(&Error::INVALID_INT_OPERAND.extract());
-            let from = from
-                .get_float()
-                .expect(&Error::INVALID_INT_OPERAND.extract());
-            self.acc = VenObjects::Bool(f(to, from));
+    /// Applies `f` to two numbers and writes the result to `acc` and the
+    /// first operand. Two ints give an int, anything involving a float gives
+    /// a float.
+    fn operate_int<F: Fn(f64, f64) -> f64>(
+        &mut self,
+        i: usize,
+        tokens: &[Instructions],
+        f: F,
+    ) -> Result<(), RuntimeError> {
+        let to = self.get_reg(&tokens[i + 1])?;
+        let from = self.get_reg(&tokens[i + 2])?;
+        self.acc = match (&to, &from) {
+            (VenObjects::Int(to), VenObjects::Int(from)) => {
+                VenObjects::Int(f(*to as f64, *from as f64).round() as i64)
+            }
+            _ => match (to.as_float(), from.as_float()) {
+                (Some(to), Some(from)) => VenObjects::Float(f(to, from)),
+                _ => {
+                    return Err(Error::INVALID_INT_OPERAND.with(format!("{:?}, {:?}", to, from)));
+                }
+            },
+        };
+        if let Some(rid) = self.get_reg_id(&tokens[i + 1]) {
+            self.write_register(rid, self.acc.clone())?;
         }
+        Ok(())
     }
 
-    fn operate_int<F: Fn(f64, f64) -> f64>(&mut self, i: usize, tokens: &[Instructions], f: F) {
-        let to = self.get_reg(&tokens[i + 1]);
-        let from = self.get_reg(&tokens[i + 2]).get_int();
-        if let Some(to) = to.get_int() {
-            if let Some(from) = from {
-                self.acc = VenObjects::Int(f(to as f64, from as f64).round() as i64);
-                if let Some(rid) = self.get_reg_id(&tokens[i + 1]) {
-                    self.registers[rid] = self.acc.clone();
-                }
-            } else {
-                let from = tokens[i + 2]
-                    .get_val_or_reg_val(&self.registers, &self.acc, &self.aliases)
-                    .get_float()
-                    .unwrap();
-                self.acc = VenObjects::Float(f(to as f64, from));
-                if let Some(rid) = self.get_reg_id(&tokens[i + 1]) {
-                    self.registers[rid] = self.acc.clone();
-                }
-            }
-        } else {
-            let to = tokens[i + 1]
-                .get_val_or_reg_val(&self.registers, &self.acc, &self.aliases)
-                .get_float()
-                .unwrap();
-            let from = tokens[i + 2]
-                .get_val_or_reg_val(&self.registers, &self.acc, &self.aliases)
-                .get_float()
-                .unwrap();
+    fn operate_bool<F: Fn(bool, bool) -> bool>(
+        &mut self,
+        i: usize,
+        tokens: &[Instructions],
+        f: F,
+    ) -> Result<(), RuntimeError> {
+        let to = self.get_bool(&tokens[i + 1])?;
+        let from = self.get_bool(&tokens[i + 2])?;
+        self.acc = VenObjects::Bool(f(to, from));
+        Ok(())
+    }
+}
 
-            self.acc = VenObjects::Float(f(to, from));
-            if let Some(rid) = self.get_reg_id(&tokens[i + 1]) {
-                self.registers[rid] = self.acc.clone();
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `body` as the `main` block and returns the CPU afterwards.
+    fn run(body: &str) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.init(10).unwrap();
+        cpu.quiet = true;
+        cpu.parse_instructions(format!("block main:\n{}\nend\n", body))
+            .unwrap_or_else(|errors| panic!("{:?}", errors));
+        cpu.exec(None).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn while_first_in_a_times_body_does_not_end_the_times() {
+        let cpu = run("mov r1, 0\n\
+             times 3\n\
+                 while false\n\
+                 endwhile\n\
+                 add r1, 1\n\
+             endtimes");
+        assert_eq!(cpu.registers[1], VenObjects::Int(3));
+    }
+
+    #[test]
+    fn while_first_in_a_for_body_does_not_end_the_for() {
+        let cpu = run("mov r1, 0\n\
+             for r2, 0, 4, 1\n\
+                 while false\n\
+                 endwhile\n\
+                 add r1, 1\n\
+             endfor");
+        assert_eq!(cpu.registers[1], VenObjects::Int(4));
+    }
+
+    #[test]
+    fn one_line_if_and_times_run_a_single_statement() {
+        let cpu = run("mov r1, 0\n\
+             mov r2, 0\n\
+             if false add r1, 1; else add r1, 10;\n\
+             times 3 add r2, 1\n\
+             add r2, 100");
+        assert_eq!(cpu.registers[1], VenObjects::Int(10));
+        assert_eq!(cpu.registers[2], VenObjects::Int(103));
+    }
+
+    #[test]
+    fn a_for_stepping_past_the_integer_range_ends() {
+        let cpu = run(&format!(
+            "mov r1, 0\nfor r2, {}, {}, 2\nadd r1, 1\nendfor",
+            i64::MAX - 3,
+            i64::MAX
+        ));
+        assert_eq!(cpu.registers[1], VenObjects::Int(2));
+        let cpu = run(&format!(
+            "mov r1, 0\nfor r2, {}, {}, -2\nadd r1, 1\nendfor",
+            i64::MIN + 3,
+            i64::MIN
+        ));
+        assert_eq!(cpu.registers[1], VenObjects::Int(2));
+    }
+
+    #[test]
+    fn running_a_block_shares_its_body() {
+        let mut cpu = CPU::new();
+        cpu.init(4).unwrap();
+        cpu.parse_instructions("block helper:\nyield\nend\nblock main:\nrun helper\nend\n".into())
+            .unwrap();
+        cpu.start().unwrap();
+        while cpu.frames.len() < 2 {
+            cpu.step().unwrap();
         }
+        assert!(Arc::ptr_eq(&cpu.frames[0].body, &cpu.blocks["main"]));
+        assert!(Arc::ptr_eq(&cpu.frames[1].body, &cpu.blocks["helper"]));
     }
 
-    fn operate_bool<F: Fn(bool, bool) -> bool>(&mut self, i: usize, tokens: &[Instructions], f: F) {
-        let to = self
-            .get_reg(&tokens[i + 1])
-            .get_bool()
-            .expect(&Error::INVALID_BOOL_OPERAND.extract());
-        let from = self
-            .get_reg(&tokens[i + 2])
-            .get_bool()
-            .expect(&Error::INVALID_BOOL_OPERAND.extract());
-        self.acc = VenObjects::Bool(f(to, from));
+    #[test]
+    fn nested_loops_with_break_and_continue() {
+        // counts the pairs (i, j) with j < i, skipping i == 2, for i < 5
+        let cpu = run("mov r1, 0\n\
+             for r2, 0, 10, 1\n\
+                 eq r2, 5\n\
+                 if acc\n\
+                     break\n\
+                 endif\n\
+                 eq r2, 2\n\
+                 if acc\n\
+                     continue\n\
+                 endif\n\
+                 mov r3, 0\n\
+                 while true\n\
+                     lt r3, r2\n\
+                     if acc\n\
+                         add r1, 1\n\
+                     else\n\
+                         break\n\
+                     endif\n\
+                     add r3, 1\n\
+                 endwhile\n\
+             endfor");
+        assert_eq!(cpu.registers[1], VenObjects::Int(1 + 3 + 4));
+    }
+
+    #[test]
+    fn times_with_a_counter_inside_if_and_else() {
+        let cpu = run("mov r1, 0\n\
+             mov r2, 0\n\
+             times 4 as r3\n\
+                 lt r3, 2\n\
+                 if acc\n\
+                     add r1, r3\n\
+                 else\n\
+                     add r2, r3\n\
+                 endif\n\
+             endtimes");
+        assert_eq!(cpu.registers[1], VenObjects::Int(1));
+        assert_eq!(cpu.registers[2], VenObjects::Int(2 + 3));
+    }
+
+    #[test]
+    fn loops_nested_three_deep() {
+        let cpu = run("mov r1, 0\n\
+             times 2\n\
+                 for r2, 0, 3, 1\n\
+                     mov r3, 0\n\
+                     while true\n\
+                         add r3, 1\n\
+                         add r1, 1\n\
+                         eq r3, 2\n\
+                         if acc\n\
+                             break\n\
+                         endif\n\
+                     endwhile\n\
+                 endfor\n\
+             endtimes");
+        assert_eq!(cpu.registers[1], VenObjects::Int(2 * 3 * 2));
     }
 }
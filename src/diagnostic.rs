@@ -0,0 +1,101 @@
+use crate::error::Error;
+use crate::json::Json;
+use std::fmt;
+
+/// A 1-based line and column in a source file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found while loading or checking a program.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub file: Option<String>,
+    pub span: Option<Span>,
+    pub block: Option<String>,
+    /// The error this diagnostic stops loading with, `PARSE_ERROR` unless
+    /// the problem is e.g. an unreadable file.
+    pub kind: Error,
+}
+
+impl Diagnostic {
+    pub fn error(span: Option<Span>, message: String) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message,
+            file: None,
+            span,
+            block: None,
+            kind: Error::PARSE_ERROR,
+        }
+    }
+    pub fn warning(span: Option<Span>, message: String) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Self::error(span, message)
+        }
+    }
+    pub fn with_kind(self, kind: Error) -> Self {
+        Diagnostic { kind, ..self }
+    }
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+    pub(crate) fn to_json(&self) -> Json {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let optional = |value: Option<Json>| value.unwrap_or(Json::Null);
+        Json::object([
+            ("severity", Json::str(severity)),
+            ("kind", Json::str(format!("{:?}", self.kind))),
+            ("message", Json::str(self.message.as_str())),
+            ("file", optional(self.file.clone().map(Json::Str))),
+            (
+                "line",
+                optional(self.span.map(|span| Json::number(span.line))),
+            ),
+            (
+                "column",
+                optional(self.span.map(|span| Json::number(span.col))),
+            ),
+            ("block", optional(self.block.clone().map(Json::Str))),
+        ])
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Error => write!(f, "error")?,
+            Severity::Warning => write!(f, "warning")?,
+        }
+        match (&self.file, &self.span) {
+            (Some(file), Some(span)) => write!(f, ": {}:{}", file, span)?,
+            (Some(file), None) => write!(f, ": {}", file)?,
+            (None, Some(span)) => write!(f, ": {}", span)?,
+            (None, None) => {}
+        }
+        if let Some(block) = &self.block {
+            write!(f, " in block `{}`", block)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
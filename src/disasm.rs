@@ -0,0 +1,78 @@
+use crate::{cpu::CPU, insts::Instructions};
+
+impl CPU {
+    /// Renders every loaded block as one instruction per line, with the
+    /// operand count of each instruction taken from its descriptor.
+    pub fn disassemble(&self) -> String {
+        let mut names: Vec<&String> = self.blocks.keys().collect();
+        names.sort();
+        let mut out = String::new();
+        for name in names {
+            if let Some(doc) = self.docs.get(name) {
+                for line in doc.lines() {
+                    out.push_str(&format!("/// {}\n", line));
+                }
+            }
+            out.push_str(&format!("block {}:\n", name));
+            out.push_str(&disassemble_block(&self.blocks[name]));
+            out.push_str("end\n\n");
+        }
+        out
+    }
+}
+
+pub fn disassemble_block(block: &[Instructions]) -> String {
+    let mut out = String::new();
+    let mut i = 0;
+    while i < block.len() {
+        if block[i] == Instructions::EOL {
+            i += 1;
+            continue;
+        }
+        let (line, width) = disassemble_at(block, i);
+        out.push_str(&line);
+        out.push('\n');
+        i += 1 + width;
+    }
+    out
+}
+
+/// Renders the instruction at `i` as one line and returns it with the
+/// number of operand tokens that follow it.
+pub fn disassemble_at(block: &[Instructions], i: usize) -> (String, usize) {
+    let token = &block[i];
+    let width = match token.descriptor() {
+        Some(descriptor)
+            if *token == Instructions::TIMES && block.get(i + 2) == Some(&Instructions::AS) =>
+        {
+            descriptor.arity() + 2
+        }
+        Some(descriptor)
+            if matches!(token, Instructions::DECLARE | Instructions::DECLARE_RO)
+                && matches!(block.get(i + 3), Some(Instructions::TYPE(_))) =>
+        {
+            descriptor.arity() + 1
+        }
+        Some(descriptor) => descriptor.arity(),
+        None => 0,
+    };
+    let operands: Vec<String> = block
+        .iter()
+        .skip(i + 1)
+        .take(width)
+        .map(|operand| match operand {
+            Instructions::TYPE(ven_type) => format!(": {}", ven_type),
+            operand => operand.to_string(),
+        })
+        .collect();
+    let line = format!(
+        "{:04}  {:<8} {}",
+        i,
+        token.to_string(),
+        operands
+            .join(", ")
+            .replace(", as, ", " as ")
+            .replace(", :", ":")
+    );
+    (line.trim_end().to_string(), width)
+}
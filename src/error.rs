@@ -1,7 +1,25 @@
 #![allow(non_camel_case_types)]
-use std::process::exit;
+use crate::diagnostic::Diagnostic;
+use crate::json::Json;
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-#[derive(Debug)]
+/// Whether errors that end the process are reported in JSON, see
+/// `set_json_errors`.
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+pub fn json_errors() -> bool {
+    JSON_ERRORS.load(Ordering::Relaxed)
+}
+
+/// Makes errors that end the process print a JSON summary instead of text.
+pub fn set_json_errors(enabled: bool) {
+    JSON_ERRORS.store(enabled, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Error {
     INVALID_REGISTER_OR_VALUE,
     INVALID_BLOCK_SYNTAX,
@@ -12,6 +30,28 @@ pub enum Error {
     INVALID_BOOL_OPERAND,
     INVALID_DECLARATION,
     CANNOT_DECLARE_ACC,
+    CANNOT_READ_FILE,
+    IMPORT_CYCLE,
+    UNKNOWN_DIRECTIVE,
+    INVALID_DIRECTIVE,
+    STACK_OVERFLOW,
+    UNDECLARED_ALIAS,
+    UNINITIALIZED_REGISTER,
+    INVALID_MACRO,
+    MACRO_EXPANSION,
+    INVALID_FOR_LOOP_SYNTAX,
+    INVALID_LITERAL,
+    TYPE_MISMATCH,
+    READ_ONLY_REGISTER,
+    INSTRUCTION_LIMIT,
+    TIME_LIMIT,
+    MEMORY_LIMIT,
+    SANDBOX_VIOLATION,
+    OUTPUT_LIMIT,
+    CANCELLED,
+    INVALID_SNAPSHOT,
+    NO_ENTRY_POINT,
+    PARSE_ERROR,
 }
 
 impl Error {
@@ -26,18 +66,125 @@ impl Error {
             Self::INVALID_TIMES_LOOP_SYNTAX => "INVALID `times loop` SYNTAX".into(),
             Self::INVALID_BOOL_OPERAND => "INVALID BOOL OPERANDS".into(),
             Self::CANNOT_DECLARE_ACC => "CANNOT DECLARE ACC".into(),
+            Self::CANNOT_READ_FILE => "CANNOT READ FILE".into(),
+            Self::IMPORT_CYCLE => "IMPORT CYCLE DETECTED".into(),
+            Self::UNKNOWN_DIRECTIVE => "UNKNOWN DIRECTIVE".into(),
+            Self::INVALID_DIRECTIVE => "INVALID DIRECTIVE SYNTAX".into(),
+            Self::STACK_OVERFLOW => "STACK OVERFLOW".into(),
+            Self::UNDECLARED_ALIAS => "UNDECLARED ALIAS".into(),
+            Self::UNINITIALIZED_REGISTER => "READ OF UNINITIALIZED REGISTER".into(),
+            Self::INVALID_MACRO => "INVALID MACRO DEFINITION".into(),
+            Self::MACRO_EXPANSION => "MACRO EXPANSION FAILED".into(),
+            Self::INVALID_FOR_LOOP_SYNTAX => "INVALID `for loop` SYNTAX".into(),
+            Self::INVALID_LITERAL => "INVALID LITERAL".into(),
+            Self::TYPE_MISMATCH => "TYPE MISMATCH".into(),
+            Self::READ_ONLY_REGISTER => "WRITE TO READ-ONLY REGISTER".into(),
+            Self::INSTRUCTION_LIMIT => "INSTRUCTION LIMIT EXCEEDED".into(),
+            Self::TIME_LIMIT => "TIME LIMIT EXCEEDED".into(),
+            Self::MEMORY_LIMIT => "MEMORY LIMIT EXCEEDED".into(),
+            Self::SANDBOX_VIOLATION => "SANDBOX VIOLATION".into(),
+            Self::OUTPUT_LIMIT => "OUTPUT LIMIT EXCEEDED".into(),
+            Self::CANCELLED => "EXECUTION CANCELLED".into(),
+            Self::INVALID_SNAPSHOT => "INVALID SNAPSHOT".into(),
+            Self::NO_ENTRY_POINT => "NO ENTRY POINT".into(),
+            Self::PARSE_ERROR => "PARSE ERROR".into(),
+        }
+    }
+    /// Builds the error returned to the host when running a program.
+    pub fn with<S: Into<String>>(self, message: S) -> RuntimeError {
+        RuntimeError {
+            kind: self,
+            message: message.into(),
+        }
+    }
+    /// The process exit code for this error. These are stable: codes 70-79
+    /// are load errors, 80-99 runtime errors and 100-109 limits and the
+    /// sandbox, so they never clash with 1 and 2 used by the command line.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::PARSE_ERROR => 70,
+            Self::CANNOT_READ_FILE => 71,
+            Self::IMPORT_CYCLE => 72,
+            Self::UNKNOWN_DIRECTIVE => 73,
+            Self::INVALID_DIRECTIVE => 74,
+            Self::INVALID_MACRO => 75,
+            Self::MACRO_EXPANSION => 76,
+            Self::INVALID_LITERAL => 77,
+            Self::NO_ENTRY_POINT => 78,
+            Self::INVALID_SNAPSHOT => 79,
+            Self::INVALID_REGISTER_OR_VALUE => 80,
+            Self::INVALID_BLOCK_SYNTAX => 81,
+            Self::INVALID_INT_OPERAND => 82,
+            Self::INVALID_VALUE_FOR_MOVE => 83,
+            Self::INVALID_RUN_BLOCK_SYNTAX => 84,
+            Self::INVALID_TIMES_LOOP_SYNTAX => 85,
+            Self::INVALID_FOR_LOOP_SYNTAX => 86,
+            Self::INVALID_BOOL_OPERAND => 87,
+            Self::INVALID_DECLARATION => 88,
+            Self::CANNOT_DECLARE_ACC => 89,
+            Self::UNDECLARED_ALIAS => 90,
+            Self::UNINITIALIZED_REGISTER => 91,
+            Self::TYPE_MISMATCH => 92,
+            Self::READ_ONLY_REGISTER => 93,
+            Self::STACK_OVERFLOW => 100,
+            Self::INSTRUCTION_LIMIT => 101,
+            Self::TIME_LIMIT => 102,
+            Self::MEMORY_LIMIT => 103,
+            Self::OUTPUT_LIMIT => 104,
+            Self::SANDBOX_VIOLATION => 105,
+            Self::CANCELLED => 106,
+        }
+    }
+    /// `load`, `runtime` or `limit`, following the ranges of `exit_code`.
+    pub fn category(&self) -> &'static str {
+        match self.exit_code() {
+            70..=79 => "load",
+            80..=99 => "runtime",
+            _ => "limit",
         }
     }
-    pub fn throw(error: Self, errormsg: Option<&str>) {
-        match errormsg {
-            Some(msg) => {
-                eprintln!("{}: {}", error.extract(), msg);
-                exit(69);
-            }
-            None => {
-                eprintln!("{}", error.extract());
-                exit(69);
-            }
+}
+
+/// A one-line JSON description of a failure, as printed with
+/// `--error-format=json`.
+pub fn json_summary(kind: Error, message: &str, diagnostics: &[Diagnostic]) -> String {
+    let mut members = vec![
+        ("kind".to_string(), Json::str(format!("{:?}", kind))),
+        ("category".to_string(), Json::str(kind.category())),
+        ("code".to_string(), Json::number(kind.exit_code())),
+        ("message".to_string(), Json::str(message)),
+    ];
+    if !diagnostics.is_empty() {
+        let diagnostics = diagnostics.iter().map(Diagnostic::to_json).collect();
+        members.push(("diagnostics".to_string(), Json::Array(diagnostics)));
+    }
+    Json::Object(members).to_string()
+}
+
+/// An error that stopped a running program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub kind: Error,
+    pub message: String,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.message.is_empty() {
+            write!(f, "{}", self.kind.extract())
+        } else {
+            write!(f, "{}: {}", self.kind.extract(), self.message)
         }
     }
 }
+
+impl RuntimeError {
+    pub fn exit_code(&self) -> i32 {
+        self.kind.exit_code()
+    }
+    pub fn to_json(&self) -> String {
+        json_summary(self.kind, &self.message, &[])
+    }
+}
+
+impl std::error::Error for RuntimeError {}
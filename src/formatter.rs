@@ -0,0 +1,522 @@
+use crate::{
+    diagnostic::Diagnostic,
+    insts::Instructions,
+    lexer::{lex_with_comments, Token, TokenKind},
+};
+use std::collections::BTreeMap;
+
+/// One line of formatted output.
+#[derive(Default)]
+struct Line {
+    depth: usize,
+    head: String,
+    operands: Vec<String>,
+    comment: Option<String>,
+    /// Takes part in operand alignment with its neighbours.
+    align: bool,
+}
+
+struct Formatter {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// `#` directive lines by line number, emitted untouched.
+    directives: BTreeMap<usize, String>,
+    lines: Vec<Line>,
+    depth: usize,
+    /// Line of the last token that was written out.
+    last_line: usize,
+    /// One-line `if cond stmt;` and `times N stmt` forms still open: the
+    /// depth of their body and the closer written out when they end.
+    single: Vec<(usize, &'static str)>,
+}
+
+impl Formatter {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+    /// Writes every directive that comes before `line`.
+    fn flush_directives(&mut self, line: usize) {
+        while let Some((&at, _)) = self.directives.range(..line).next() {
+            let text = self.directives.remove(&at).unwrap_or_default();
+            self.start_line(at);
+            self.lines.push(Line {
+                head: text,
+                ..Line::default()
+            });
+        }
+    }
+    /// Keeps a single blank line wherever the source had one or more.
+    fn start_line(&mut self, line: usize) {
+        if line > self.last_line + 1
+            && self
+                .lines
+                .last()
+                .is_some_and(|last| !last.head.is_empty() || last.comment.is_some())
+        {
+            self.lines.push(Line::default());
+        }
+        self.last_line = line;
+    }
+    /// Called before the first token of a new output line is consumed.
+    fn begin(&mut self, line: usize) {
+        self.flush_directives(line);
+        self.start_line(line);
+    }
+    fn push(&mut self, depth: usize, head: String, operands: Vec<String>, align: bool) {
+        self.lines.push(Line {
+            depth,
+            head,
+            operands,
+            comment: None,
+            align,
+        });
+    }
+    /// Takes up to `count` operands, stopping at the end of the statement.
+    fn operands(&mut self, count: usize) -> Vec<String> {
+        let mut operands = vec![];
+        while operands.len() < count {
+            let Some(token) = self.peek().cloned() else {
+                break;
+            };
+            let operand = match &token.kind {
+                TokenKind::Comma => {
+                    self.pos += 1;
+                    continue;
+                }
+                TokenKind::Word(word) if is_statement(word) => break,
+                TokenKind::Word(word) => operand_word(word),
+                TokenKind::Str(stri) => quote(stri),
+                TokenKind::Int(_) | TokenKind::Float(_) => token.text.clone(),
+                _ => break,
+            };
+            self.pos += 1;
+            self.last_line = token.span.line;
+            operands.push(operand);
+        }
+        operands
+    }
+    /// Whether the `if` or `times` just read from `line` is in its one-line
+    /// form: a statement follows on the same line, which has no closer for
+    /// it. The parser inserts the closer the same way.
+    fn is_single(&self, line: usize, closer: &str) -> bool {
+        let on_line = |t: &&Token| t.span.line == line;
+        let starts_body = self.peek().filter(on_line).is_some_and(|t| {
+            !matches!(
+                t.kind,
+                TokenKind::Newline | TokenKind::Semicolon | TokenKind::Comment(_)
+            )
+        });
+        let mut depth = 0;
+        let closing = self.tokens[self.pos..]
+            .iter()
+            .take_while(|t| on_line(t))
+            .find_map(|t| {
+                let TokenKind::Word(word) = &t.kind else {
+                    return None;
+                };
+                match word.to_lowercase().as_str() {
+                    "if" | "while" | "times" | "for" => depth += 1,
+                    "endif" | "endwhile" | "endtimes" | "endfor" => depth -= 1,
+                    _ => {}
+                }
+                (depth < 0).then(|| word.to_lowercase())
+            });
+        starts_body && closing.as_deref() != Some(closer)
+    }
+    /// Writes the closer of the innermost one-line form if its body is the
+    /// current depth.
+    fn close_single(&mut self) -> bool {
+        match self.single.last() {
+            Some(&(depth, closer)) if depth == self.depth => {
+                self.single.pop();
+                self.depth -= 1;
+                self.push(self.depth, closer.into(), vec![], false);
+                true
+            }
+            _ => false,
+        }
+    }
+    fn statement(&mut self, token: Token, word: &str) {
+        self.begin(token.span.line);
+        let lower = word.to_lowercase();
+        match lower.as_str() {
+            "block" if self.depth == 0 => {
+                let name = match self.peek().map(|t| t.kind.clone()) {
+                    Some(TokenKind::Word(name)) => {
+                        self.pos += 1;
+                        name
+                    }
+                    _ => String::new(),
+                };
+                if self.peek().is_some_and(|t| t.kind == TokenKind::Colon) {
+                    self.pos += 1;
+                }
+                self.push(0, format!("block {}:", name), vec![], false);
+                self.depth = 1;
+                return;
+            }
+            "import" if self.depth == 0 => {
+                let operands = self.operands(1);
+                self.push(0, "import".into(), operands, false);
+                return;
+            }
+            "end" => {
+                self.depth = 0;
+                self.single.clear();
+                self.push(0, lower, vec![], false);
+                return;
+            }
+            "macro" => {
+                let mut header = vec![];
+                while let Some(next) = self.peek().cloned() {
+                    match next.kind {
+                        TokenKind::Word(param) => header.push(param),
+                        TokenKind::Comma => {}
+                        TokenKind::Colon => {
+                            self.pos += 1;
+                            break;
+                        }
+                        _ => break,
+                    }
+                    self.pos += 1;
+                }
+                let name = if header.is_empty() {
+                    String::new()
+                } else {
+                    header.remove(0)
+                };
+                let head = if header.is_empty() {
+                    format!("macro {}:", name)
+                } else {
+                    format!("macro {} {}:", name, header.join(", "))
+                };
+                self.push(0, head, vec![], false);
+                self.depth = 1;
+                return;
+            }
+            "endmacro" => {
+                self.depth = 0;
+                self.push(0, lower, vec![], false);
+                return;
+            }
+            _ => {}
+        }
+        let inst = Instructions::build_from_str(&lower);
+        let Some(descriptor) = inst.descriptor() else {
+            // a macro call: everything up to the end of the statement
+            let operands = self.operands(usize::MAX);
+            self.push(self.depth, word.to_string(), operands, true);
+            return;
+        };
+        let read_only = inst == Instructions::DECLARE
+            && self.peek().is_some_and(
+                |t| matches!(&t.kind, TokenKind::Word(w) if w.eq_ignore_ascii_case("ro")),
+            );
+        if read_only {
+            self.pos += 1;
+        }
+        let mut operands = self.operands(descriptor.arity());
+        if inst == Instructions::TIMES
+            && self.peek().is_some_and(
+                |t| matches!(&t.kind, TokenKind::Word(w) if w.eq_ignore_ascii_case("as")),
+            )
+        {
+            self.pos += 1;
+            let counter = self.operands(1).join("");
+            match operands.last_mut() {
+                Some(count) => count.push_str(&format!(" as {}", counter)),
+                None => operands.push(format!("as {}", counter)),
+            }
+        }
+        if inst == Instructions::DECLARE && self.peek().is_some_and(|t| t.kind == TokenKind::Colon)
+        {
+            self.pos += 1;
+            let ven_type = self.operands(1).join("").to_lowercase();
+            if let Some(alias) = operands.last_mut() {
+                alias.push_str(&format!(": {}", ven_type));
+            }
+        }
+        let head = if read_only {
+            "declare ro".to_string()
+        } else {
+            descriptor.mnemonic.to_string()
+        };
+        match inst {
+            Instructions::IF | Instructions::WHILE | Instructions::TIMES | Instructions::FOR => {
+                self.push(self.depth, head, operands, false);
+                self.depth += 1;
+                let closer = match inst {
+                    Instructions::IF => "endif",
+                    _ => "endtimes",
+                };
+                if matches!(inst, Instructions::IF | Instructions::TIMES)
+                    && self.is_single(token.span.line, closer)
+                {
+                    self.single.push((self.depth, closer));
+                }
+            }
+            Instructions::ELSE => {
+                self.push(self.depth.saturating_sub(1), head, operands, false);
+            }
+            Instructions::ENDIF
+            | Instructions::ENDWHILE
+            | Instructions::ENDTIMES
+            | Instructions::ENDFOR => {
+                // a one-line form ends before the closer of what encloses it
+                while self.close_single() {}
+                self.depth = self.depth.saturating_sub(1).max(1);
+                self.push(self.depth, head, operands, false);
+            }
+            _ => self.push(self.depth, head, operands, true),
+        }
+    }
+    fn comment(&mut self, token: &Token, text: String) {
+        let trailing = token.span.line == self.last_line
+            && self
+                .lines
+                .last()
+                .is_some_and(|last| !last.head.is_empty() && last.comment.is_none());
+        if trailing {
+            if let Some(last) = self.lines.last_mut() {
+                last.comment = Some(text);
+            }
+            return;
+        }
+        self.begin(token.span.line);
+        self.last_line += text.matches('\n').count();
+        self.lines.push(Line {
+            depth: self.depth,
+            comment: Some(text),
+            ..Line::default()
+        });
+    }
+    fn run(&mut self) {
+        while let Some(token) = self.tokens.get(self.pos).cloned() {
+            self.pos += 1;
+            match &token.kind {
+                TokenKind::Newline => while self.close_single() {},
+                // `if cond a; else b;` keeps going after the first `;`
+                TokenKind::Semicolon
+                    if !self.peek().is_some_and(|next| {
+                        next.span.line == token.span.line
+                            && matches!(&next.kind, TokenKind::Word(w) if w.eq_ignore_ascii_case("else"))
+                            && self.single.last().is_some_and(|s| s.1 == "endif")
+                    }) =>
+                {
+                    self.close_single();
+                }
+                TokenKind::Semicolon | TokenKind::Comma => {}
+                TokenKind::Comment(text) => self.comment(&token, text.clone()),
+                TokenKind::Doc(text) if text.is_empty() => self.comment(&token, "///".into()),
+                TokenKind::Doc(text) => self.comment(&token, format!("/// {}", text)),
+                TokenKind::Word(word) => self.statement(token.clone(), word),
+                TokenKind::Colon | TokenKind::Str(_) | TokenKind::Int(_) | TokenKind::Float(_) => {
+                    // stray tokens are kept as they are for the parser to report
+                    self.begin(token.span.line);
+                    let text = match &token.kind {
+                        TokenKind::Colon => ":".to_string(),
+                        TokenKind::Str(stri) => quote(stri),
+                        _ => token.text.clone(),
+                    };
+                    self.push(self.depth, text, vec![], false);
+                }
+            }
+        }
+        while self.close_single() {}
+        self.flush_directives(usize::MAX);
+    }
+    /// Renders the lines, lining up the operands of consecutive statements at
+    /// the same depth.
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let mut i = 0;
+        while i < self.lines.len() {
+            let mut end = i + 1;
+            if self.lines[i].align {
+                while end < self.lines.len()
+                    && self.lines[end].align
+                    && self.lines[end].depth == self.lines[i].depth
+                {
+                    end += 1;
+                }
+            }
+            let width = self.lines[i..end]
+                .iter()
+                .filter(|line| !line.operands.is_empty())
+                .map(|line| line.head.len())
+                .max()
+                .unwrap_or(0);
+            for line in self.lines[i..end].iter() {
+                let mut text = "\t".repeat(line.depth);
+                if line.operands.is_empty() {
+                    text.push_str(&line.head);
+                } else {
+                    text.push_str(&format!(
+                        "{:<width$} {}",
+                        line.head,
+                        line.operands.join(", "),
+                        width = width
+                    ));
+                }
+                if let Some(comment) = &line.comment {
+                    if !line.head.is_empty() {
+                        text.push(' ');
+                    }
+                    text.push_str(comment);
+                }
+                out.push_str(text.trim_end_matches([' ', '\t']));
+                out.push('\n');
+            }
+            i = end;
+        }
+        out
+    }
+}
+
+/// Whether `word` starts a new statement rather than being an operand.
+fn is_statement(word: &str) -> bool {
+    let lower = word.to_lowercase();
+    Instructions::build_from_str(&lower).descriptor().is_some()
+        || matches!(
+            lower.as_str(),
+            "end" | "block" | "import" | "macro" | "endmacro" | "const"
+        )
+}
+
+/// Keywords and registers are written in lowercase, names as they are.
+fn operand_word(word: &str) -> String {
+    let lower = word.to_lowercase();
+    match Instructions::build_from_str(&lower) {
+        Instructions::IDENT(_) => word.to_string(),
+        _ => lower,
+    }
+}
+
+fn quote(stri: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in stri.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Re-emits a source file in canonical form: one statement per line,
+/// indented with tabs by nesting, operands separated by `, ` and lined up
+/// across neighbouring statements, `block name:` headers and no trailing
+/// semicolons. Comments and `#` directives are kept where they were.
+///
+/// Fails with the lexer's diagnostics if the source cannot be tokenized.
+pub fn format_source(src: &str) -> Result<String, Vec<Diagnostic>> {
+    let mut directives = BTreeMap::new();
+    let stripped: Vec<&str> = src
+        .lines()
+        .enumerate()
+        .map(|(no, line)| {
+            if line.trim_start().starts_with('#') {
+                directives.insert(no + 1, line.trim().to_string());
+                ""
+            } else {
+                line
+            }
+        })
+        .collect();
+    let (tokens, diagnostics) = lex_with_comments(&stripped.join("\n"));
+    if diagnostics.iter().any(Diagnostic::is_error) {
+        return Err(diagnostics);
+    }
+    let mut formatter = Formatter {
+        tokens,
+        pos: 0,
+        directives,
+        lines: vec![],
+        single: vec![],
+        depth: 0,
+        last_line: 0,
+    };
+    formatter.run();
+    Ok(formatter.render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    const MESSY: &str = r#"#r10!
+// counts to three
+macro twice x:
+  add x, x
+endmacro
+block   main
+   DECLARE r1,n:int ; mov n,1;twice n
+ times 3 as n print n ; endtimes
+  for r2,0,10,3
+ if r2 println "big;  // not a comment"; else print 'x' endif /* inline */
+endfor
+    declare ro r3,limit ;; trailing
+  while false break endwhile
+end
+/// Runs nothing.
+block helper:
+run main ;
+end
+"#;
+
+    /// The disassembly without instruction offsets, which move when `;`
+    /// separators become line breaks.
+    fn disassemble(src: &str) -> Vec<String> {
+        let mut cpu = CPU::new();
+        cpu.parse_instructions(src.to_string())
+            .unwrap_or_else(|errors| panic!("{:?}", errors));
+        cpu.disassemble()
+            .lines()
+            .map(|line| match line.split_once("  ") {
+                Some((offset, rest)) if offset.bytes().all(|b| b.is_ascii_digit()) => {
+                    rest.to_string()
+                }
+                _ => line.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        for src in [MESSY, include_str!("../test/main.ben")] {
+            let once = format_source(src).unwrap();
+            assert_eq!(format_source(&once).unwrap(), once);
+        }
+    }
+
+    #[test]
+    fn formatting_keeps_the_program() {
+        for src in [MESSY, include_str!("../test/main.ben")] {
+            let formatted = format_source(src).unwrap();
+            assert_eq!(disassemble(&formatted), disassemble(src));
+        }
+    }
+
+    #[test]
+    fn one_line_forms_are_written_as_blocks() {
+        let src = "block main:\nif acc println 1; else println 2;\ntimes 2 println 3\nend\n";
+        let formatted = format_source(src).unwrap();
+        assert_eq!(
+            formatted,
+            "block main:\n\tif acc\n\t\tprintln 1\n\telse\n\t\tprintln 2\n\tendif\n\
+             \ttimes 2\n\t\tprintln 3\n\tendtimes\nend\n"
+        );
+        assert_eq!(disassemble(&formatted), disassemble(src));
+    }
+
+    #[test]
+    fn formatting_fails_on_lexer_errors() {
+        let errors = format_source("block main:\nmov r1, \"open\nend\n").unwrap_err();
+        assert!(errors.iter().any(Diagnostic::is_error));
+    }
+}
@@ -1,8 +1,8 @@
-#![allow(non_camel_case_types, dead_code)]
+#![allow(non_camel_case_types)]
 
-use std::{collections::HashMap, i64, process::exit};
+use std::fmt;
 
-use crate::venobjects::VenObjects;
+use crate::venobjects::{VenObjects, VenType};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Instructions {
@@ -18,6 +18,10 @@ pub enum Instructions {
     PRINT,
     MOV,
     DECLARE,
+    /// `declare ro`: an alias whose register cannot be written afterwards.
+    DECLARE_RO,
+    /// `global rN, name`: an alias visible from every block.
+    GLOBAL,
     KEYWORD(String),
     BLOCK(String, Vec<Instructions>),
     RUN,
@@ -25,6 +29,20 @@ pub enum Instructions {
     TIMES,
     IF,
     ELSE,
+    ENDIF,
+    WHILE,
+    ENDWHILE,
+    ENDTIMES,
+    AS,
+    FOR,
+    ENDFOR,
+    BREAK,
+    CONTINUE,
+    YIELD,
+    HALT,
+    ARGC,
+    ARGV,
+    GETENV,
     TRUE,
     FALSE,
     EQ,
@@ -36,82 +54,424 @@ pub enum Instructions {
     ACC,
     EOL,
     END,
+    /// A bare name: an alias, loop counter or block name.
+    IDENT(String),
+    /// The `: int` annotation that may follow the alias of a `declare`.
+    TYPE(VenType),
     // TYPES
     DATA(VenObjects),
 }
 
+/// What a single operand of an instruction is allowed to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperandKind {
+    /// `r0`, `r1`, ...
+    Register,
+    /// The accumulator, `acc`.
+    Acc,
+    /// A bare name: an alias, or a block name for `run`.
+    Alias,
+    /// A number, string or boolean.
+    Literal,
+}
+
+use OperandKind::*;
+
+/// Somewhere a value can be written to.
+const DEST: &[OperandKind] = &[Register, Acc, Alias];
+/// Anything that produces a value.
+const VALUE: &[OperandKind] = &[Register, Acc, Alias, Literal];
+const REGISTER: &[OperandKind] = &[Register];
+const NAME: &[OperandKind] = &[Alias];
+
+/// Static description of an opcode: its mnemonic and the kinds accepted by
+/// each of its operands. Parsing, validation, execution and the
+/// disassembler all read operand counts from here.
+#[derive(Debug)]
+pub struct Descriptor {
+    pub mnemonic: &'static str,
+    pub inst: Instructions,
+    pub operands: &'static [&'static [OperandKind]],
+}
+
+impl Descriptor {
+    pub fn arity(&self) -> usize {
+        self.operands.len()
+    }
+    /// Whether operand `index` is a location this instruction writes to.
+    pub fn writes(&self, index: usize) -> bool {
+        self.operands.get(index) == Some(&DEST)
+    }
+}
+
+macro_rules! descriptors {
+    ($($mnemonic:literal => $inst:ident [$($operand:ident),*],)*) => {
+        pub static DESCRIPTORS: &[Descriptor] = &[$(Descriptor {
+            mnemonic: $mnemonic,
+            inst: Instructions::$inst,
+            operands: &[$($operand),*],
+        },)*];
+    };
+}
+
+descriptors! {
+    "add" => ADD [DEST, VALUE],
+    "sub" => SUB [DEST, VALUE],
+    "div" => DIV [DEST, VALUE],
+    "mul" => MUL [DEST, VALUE],
+    "pow" => POW [DEST, VALUE],
+    "root" => ROOT [DEST, VALUE],
+    "and" => AND [VALUE, VALUE],
+    "or" => OR [VALUE, VALUE],
+    "xor" => XOR [VALUE, VALUE],
+    "eq" => EQ [VALUE, VALUE],
+    "gt" => GT [VALUE, VALUE],
+    "lt" => LT [VALUE, VALUE],
+    "not" => NOT [VALUE],
+    "mov" => MOV [DEST, VALUE],
+    "declare" => DECLARE [REGISTER, NAME],
+    "declare ro" => DECLARE_RO [REGISTER, NAME],
+    "global" => GLOBAL [REGISTER, NAME],
+    "print" => PRINT [VALUE],
+    "println" => PRINTLN [VALUE],
+    "run" => RUN [NAME],
+    "if" => IF [VALUE],
+    "else" => ELSE [],
+    "endif" => ENDIF [],
+    "while" => WHILE [VALUE],
+    "endwhile" => ENDWHILE [],
+    "times" => TIMES [VALUE],
+    "endtimes" => ENDTIMES [],
+    "for" => FOR [DEST, VALUE, VALUE, VALUE],
+    "endfor" => ENDFOR [],
+    "break" => BREAK [],
+    "continue" => CONTINUE [],
+    "yield" => YIELD [],
+    "halt" => HALT [VALUE],
+    "argc" => ARGC [DEST],
+    "argv" => ARGV [DEST, VALUE],
+    "getenv" => GETENV [DEST, VALUE],
+}
+
 impl Instructions {
+    /// Turns a word of source text into a token. Literals never reach this:
+    /// the lexer parses them with `parse_number` and `parse_char`.
     pub fn build_from_str(stri: &str) -> Instructions {
+        if let Some(descriptor) = DESCRIPTORS.iter().find(|d| d.mnemonic == stri) {
+            return descriptor.inst.clone();
+        }
         match stri {
             "end" => Instructions::END,
-            "add" => Instructions::ADD,
-            "sub" => Instructions::SUB,
-            "div" => Instructions::DIV,
-            "mul" => Instructions::MUL,
-            "pow" => Instructions::POW,
-            "root" => Instructions::ROOT,
-            "print" => Instructions::PRINT,
-            "mov" => Instructions::MOV,
-            "declare" => Instructions::DECLARE,
             "acc" => Instructions::ACC,
-            "times" => Instructions::TIMES,
-            "run" => Instructions::RUN,
-            "else" => Instructions::ELSE,
-            "if" => Instructions::IF,
-            "println" => Instructions::PRINTLN,
-            "and" => Instructions::AND,
-            "or" => Instructions::OR,
-            "xor" => Instructions::XOR,
+            "as" => Instructions::AS,
             "true" => Instructions::TRUE,
             "false" => Instructions::FALSE,
-            "eq" => Instructions::EQ,
-            "gt" => Instructions::GT,
-            "lt" => Instructions::LT,
-            "not" => Instructions::NOT,
-            a => {
-                if let Some(int) = a.strip_prefix('r') {
-                    Instructions::REG(int.parse().expect("Error: invalid character: {a}"))
-                } else if a.starts_with('\"') && a.ends_with('\"') {
-                    Self::DATA(VenObjects::Str(a[1..(a.len() - 1)].to_string()))
-                } else if let Ok(num) = a.parse::<i64>() {
-                    Self::DATA(VenObjects::Int(num))
-                } else if let Ok(num) = a.parse::<f64>() {
-                    Self::DATA(VenObjects::Float(num))
-                } else {
-                    Instructions::DATA(VenObjects::Str(stri.to_string()))
-                }
-            }
+            "none" => Instructions::DATA(VenObjects::Empty),
+            a => match a.strip_prefix('r').map(str::parse) {
+                Some(Ok(int)) => Instructions::REG(int),
+                _ => Instructions::IDENT(stri.to_string()),
+            },
         }
     }
-    pub fn extract_value(&self) -> Option<VenObjects> {
+    pub fn descriptor(&self) -> Option<&'static Descriptor> {
+        DESCRIPTORS.iter().find(|d| d.inst == *self)
+    }
+    /// The kind of operand this token is, or `None` if it is not an operand.
+    pub fn operand_kind(&self) -> Option<OperandKind> {
         match self {
-            Self::DATA(data) => Some(data.clone()),
+            Self::REG(_) => Some(Register),
+            Self::ACC => Some(Acc),
+            Self::IDENT(_) => Some(Alias),
+            Self::DATA(_) | Self::TRUE | Self::FALSE => Some(Literal),
             _ => None,
         }
     }
-    pub fn get_val_or_reg_val(
-        &self,
-        regs: &[VenObjects],
-        acc: &VenObjects,
-        aliases: &HashMap<String, usize>,
-    ) -> VenObjects {
+    /// Whether this token may appear where `allowed` kinds are expected.
+    pub fn fits(&self, allowed: &[OperandKind]) -> bool {
+        self.operand_kind()
+            .is_some_and(|kind| allowed.contains(&kind))
+    }
+}
+
+impl fmt::Display for Instructions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(descriptor) = self.descriptor() {
+            return write!(f, "{}", descriptor.mnemonic);
+        }
         match self {
-            Self::REG(rid) => regs[*rid].clone(),
-            Self::DATA(VenObjects::Str(alias)) => {
-                if let Some(&rid) = aliases.get(alias.as_str()) {
-                    regs[rid].clone()
-                } else {
-                    VenObjects::Str(alias.to_string())
+            Self::REG(rid) => write!(f, "r{}", rid),
+            Self::ACC => write!(f, "acc"),
+            Self::TRUE => write!(f, "true"),
+            Self::FALSE => write!(f, "false"),
+            Self::AS => write!(f, "as"),
+            Self::EOL => write!(f, ";"),
+            Self::END => write!(f, "end"),
+            Self::BLOCK(name, _) => write!(f, "block {}:", name),
+            Self::KEYWORD(word) => write!(f, "{}", word),
+            Self::IDENT(name) => write!(f, "{}", name),
+            Self::TYPE(ven_type) => write!(f, "{}", ven_type),
+            Self::DATA(VenObjects::Str(stri)) => write!(f, "{:?}", stri),
+            Self::DATA(VenObjects::Int(num)) => write!(f, "{}", num),
+            Self::DATA(VenObjects::Float(float)) => write!(f, "{:?}", float),
+            Self::DATA(VenObjects::Bool(bol)) => write!(f, "{}", bol),
+            Self::DATA(VenObjects::Empty) => write!(f, "none"),
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
+
+/// Parses a numeric literal: decimal, `0x`/`0b`/`0o` integers, floats with
+/// an optional exponent, all with an optional leading `-` and `_` between
+/// digits.
+pub fn parse_number(text: &str) -> Result<VenObjects, String> {
+    let malformed = || format!("malformed number `{}`", text);
+    let (negative, unsigned) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let lower = unsigned.to_ascii_lowercase();
+    let (radix, digits) = match lower.get(..2) {
+        Some("0x") => (16, &unsigned[2..]),
+        Some("0b") => (2, &unsigned[2..]),
+        Some("0o") => (8, &unsigned[2..]),
+        _ => (10, unsigned),
+    };
+    // separators may only sit between two digits
+    let bytes = digits.as_bytes();
+    for (i, &b) in bytes.iter().enumerate() {
+        if b == b'_'
+            && !(i > 0
+                && i + 1 < bytes.len()
+                && bytes[i - 1].is_ascii_alphanumeric()
+                && bytes[i + 1].is_ascii_alphanumeric())
+        {
+            return Err(malformed());
+        }
+    }
+    let digits = digits.replace('_', "");
+    if digits.is_empty() {
+        return Err(malformed());
+    }
+    let sign = if negative { "-" } else { "" };
+    if radix != 10 {
+        return i64::from_str_radix(&format!("{}{}", sign, digits), radix)
+            .map(VenObjects::Int)
+            .map_err(|err| match err.kind() {
+                std::num::IntErrorKind::PosOverflow | std::num::IntErrorKind::NegOverflow => {
+                    format!("integer literal `{}` is out of range", text)
                 }
+                _ => malformed(),
+            });
+    }
+    if digits.bytes().all(|b| b.is_ascii_digit()) {
+        return format!("{}{}", sign, digits)
+            .parse::<i64>()
+            .map(VenObjects::Int)
+            .map_err(|_| format!("integer literal `{}` is out of range", text));
+    }
+    // `parse::<f64>` also accepts `inf` and `nan`, which are not literals
+    if !digits.starts_with(|c: char| c.is_ascii_digit())
+        || digits.contains(|c: char| c.is_ascii_alphabetic() && c != 'e' && c != 'E')
+    {
+        return Err(malformed());
+    }
+    let float = format!("{}{}", sign, digits)
+        .parse::<f64>()
+        .map_err(|_| malformed())?;
+    // too large an exponent parses as infinity
+    if !float.is_finite() {
+        return Err(format!("float literal `{}` is out of range", text));
+    }
+    Ok(VenObjects::Float(float))
+}
+
+/// Resolves the contents of a character literal, between the quotes.
+pub fn parse_char(text: &str) -> Result<char, String> {
+    let mut chars = text.chars();
+    let c = match (chars.next(), chars.next()) {
+        (Some('\\'), Some(escaped)) => match escaped {
+            'n' => '\n',
+            't' => '\t',
+            '0' => '\0',
+            c => c,
+        },
+        (Some(c), None) => return Ok(c),
+        (None, _) => return Err("empty character literal".into()),
+        _ => {
+            return Err(format!(
+                "character literal `'{}'` has more than one character",
+                text
+            ))
+        }
+    };
+    if chars.next().is_some() {
+        return Err(format!(
+            "character literal `'{}'` has more than one character",
+            text
+        ));
+    }
+    Ok(c)
+}
+
+/// Checks every instruction of a block against its descriptor: enough
+/// operands follow it and each has an allowed kind. Returns the index of the
+/// offending instruction together with a message for every problem.
+pub fn validate_operands(block: &[Instructions]) -> Vec<(usize, String)> {
+    let mut problems = vec![];
+    let mut i = 0;
+    while i < block.len() {
+        let token = &block[i];
+        let Some(descriptor) = token.descriptor() else {
+            if token.operand_kind().is_some() {
+                problems.push((i, format!("unexpected operand `{}`", token)));
+            }
+            i += 1;
+            continue;
+        };
+        let mut expected: Vec<&[OperandKind]> = descriptor.operands.to_vec();
+        if *token == Instructions::TIMES && block.get(i + 2) == Some(&Instructions::AS) {
+            expected.push(&[]);
+            expected.push(DEST);
+        }
+        let mut consumed = 0;
+        for (offset, allowed) in expected.iter().enumerate() {
+            let Some(operand) = block.get(i + 1 + offset) else {
+                break;
+            };
+            if allowed.is_empty() {
+                // the `as` of `times N as counter`
+                consumed += 1;
+                continue;
+            }
+            if operand.operand_kind().is_none() {
+                break;
             }
-            Self::DATA(data) => data.clone(),
-            Self::ACC => acc.clone(),
-            Self::TRUE => VenObjects::Bool(true),
-            Self::FALSE => VenObjects::Bool(false),
-            a => {
-                eprintln!("Incorrect register...: {:?}", a);
-                exit(69);
+            if !operand.fits(allowed) {
+                problems.push((
+                    i,
+                    format!(
+                        "operand {} of `{}` cannot be `{}`, expected {}",
+                        offset + 1,
+                        descriptor.mnemonic,
+                        operand,
+                        describe_kinds(allowed)
+                    ),
+                ));
             }
+            consumed += 1;
+        }
+        if consumed < expected.len() {
+            problems.push((
+                i,
+                format!(
+                    "`{}` expects {} operand(s), found {}",
+                    descriptor.mnemonic,
+                    expected.len(),
+                    consumed
+                ),
+            ));
+        }
+        i += 1 + consumed;
+    }
+    problems
+}
+
+fn describe_kinds(kinds: &[OperandKind]) -> String {
+    let names: Vec<&str> = kinds
+        .iter()
+        .map(|kind| match kind {
+            Register => "a register",
+            Acc => "acc",
+            Alias => "a name",
+            Literal => "a literal",
+        })
+        .collect();
+    match names.split_last() {
+        Some((last, [])) => last.to_string(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_number_literals() {
+        assert_eq!(parse_number("42"), Ok(VenObjects::Int(42)));
+        assert_eq!(parse_number("-0x1F"), Ok(VenObjects::Int(-31)));
+        assert_eq!(parse_number("0b1010"), Ok(VenObjects::Int(10)));
+        assert_eq!(parse_number("0o17"), Ok(VenObjects::Int(15)));
+        assert_eq!(parse_number("1_000"), Ok(VenObjects::Int(1000)));
+        assert_eq!(parse_number("2.5e3"), Ok(VenObjects::Float(2500.0)));
+        assert_eq!(
+            parse_number("-9223372036854775808"),
+            Ok(VenObjects::Int(i64::MIN))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_numbers() {
+        for text in [
+            "0x", "0b", "1__0", "_1", "1_", "1.5.2", "0x1g", "1inf", "1nan",
+        ] {
+            assert_eq!(
+                parse_number(text),
+                Err(format!("malformed number `{}`", text)),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_integers() {
+        for text in [
+            "9223372036854775808",
+            "-9223372036854775809",
+            "0x8000000000000000",
+            "0b1_0000000000000000000000000000000000000000000000000000000000000000",
+        ] {
+            assert_eq!(
+                parse_number(text),
+                Err(format!("integer literal `{}` is out of range", text))
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_floats() {
+        for text in ["1e400", "-1.5e309", "1_000e400"] {
+            assert_eq!(
+                parse_number(text),
+                Err(format!("float literal `{}` is out of range", text))
+            );
+        }
+        assert_eq!(parse_number("1e308"), Ok(VenObjects::Float(1e308)));
+    }
+
+    #[test]
+    fn parses_char_literals() {
+        assert_eq!(parse_char("a"), Ok('a'));
+        assert_eq!(parse_char("\\n"), Ok('\n'));
+        assert_eq!(parse_char("\\t"), Ok('\t'));
+        assert_eq!(parse_char("\\0"), Ok('\0'));
+        assert_eq!(parse_char("\\'"), Ok('\''));
+        assert_eq!(parse_char("é"), Ok('é'));
+    }
+
+    #[test]
+    fn rejects_bad_char_literals() {
+        assert_eq!(parse_char(""), Err("empty character literal".into()));
+        for text in ["ab", "\\nx", "abc"] {
+            assert_eq!(
+                parse_char(text),
+                Err(format!(
+                    "character literal `'{}'` has more than one character",
+                    text
+                ))
+            );
         }
     }
 }
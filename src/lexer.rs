@@ -0,0 +1,292 @@
+use crate::{
+    diagnostic::{Diagnostic, Span},
+    error::Error,
+    insts::{parse_char, parse_number},
+    venobjects::VenObjects,
+};
+use std::{iter::Peekable, str::Chars};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    /// A keyword, register, alias or block name. `math::sqrt` is one word.
+    Word(String),
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Colon,
+    Comma,
+    Semicolon,
+    Newline,
+    /// A `///` comment, kept so it can document the block that follows.
+    Doc(String),
+    /// Any other comment, including its delimiters. Only produced by
+    /// `lex_with_comments`.
+    Comment(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+    /// The source text of a number or character, exactly as written.
+    pub text: String,
+}
+
+struct Lexer<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+    col: usize,
+    tokens: Vec<Token>,
+    diagnostics: Vec<Diagnostic>,
+    keep_comments: bool,
+}
+
+impl Lexer<'_> {
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+    fn push(&mut self, kind: TokenKind, span: Span) {
+        self.tokens.push(Token {
+            kind,
+            span,
+            text: String::new(),
+        });
+    }
+    fn comment(&mut self, text: String, span: Span) {
+        if self.keep_comments {
+            self.push(TokenKind::Comment(text), span);
+        }
+    }
+    fn error(&mut self, span: Span, message: String) {
+        self.diagnostics
+            .push(Diagnostic::error(Some(span), message));
+    }
+    /// Reports a malformed number, character or string literal.
+    fn literal_error(&mut self, span: Span, message: String) {
+        self.diagnostics
+            .push(Diagnostic::error(Some(span), message).with_kind(Error::INVALID_LITERAL));
+    }
+    fn word(&mut self, first: char) -> String {
+        let mut word = String::from(first);
+        while let Some(c) = self.peek() {
+            if c.is_alphanumeric() || c == '_' {
+                word.push(c);
+                self.bump();
+            } else if c == ':' {
+                // `math::sqrt` is a single word, a lone `:` ends a block header
+                let mut lookahead = self.chars.clone();
+                lookahead.next();
+                if lookahead.next() != Some(':') {
+                    break;
+                }
+                self.bump();
+                self.bump();
+                word.push_str("::");
+            } else {
+                break;
+            }
+        }
+        word
+    }
+    fn number(&mut self, first: char, span: Span) {
+        let mut text = String::from(first);
+        let radix_prefixed = |text: &str| {
+            let digits = text.trim_start_matches('-').to_ascii_lowercase();
+            ["0x", "0b", "0o"].iter().any(|p| digits.starts_with(p))
+        };
+        while let Some(c) = self.peek() {
+            let exponent_sign =
+                (c == '-' || c == '+') && text.ends_with(['e', 'E']) && !radix_prefixed(&text);
+            if c.is_alphanumeric() || c == '_' || c == '.' || exponent_sign {
+                text.push(c);
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        let kind = match parse_number(&text) {
+            Ok(VenObjects::Float(float)) => TokenKind::Float(float),
+            Ok(VenObjects::Int(int)) => TokenKind::Int(int),
+            Ok(_) => unreachable!(),
+            Err(err) => {
+                self.literal_error(span, err);
+                return;
+            }
+        };
+        self.tokens.push(Token { kind, span, text });
+    }
+    /// Skips the rest of the line, leaving the newline in place.
+    fn line_comment(&mut self) -> String {
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            if c == '\n' {
+                break;
+            }
+            text.push(c);
+            self.bump();
+        }
+        text
+    }
+    fn block_comment(&mut self, span: Span) {
+        let mut text = String::from("/*");
+        loop {
+            match self.bump() {
+                Some('*') if self.peek() == Some('/') => {
+                    self.bump();
+                    text.push_str("*/");
+                    self.comment(text, span);
+                    return;
+                }
+                Some(c) => text.push(c),
+                None => {
+                    self.error(span, "unterminated block comment".into());
+                    return;
+                }
+            }
+        }
+    }
+    /// Lexes `'a'` as the integer code of the character.
+    fn char(&mut self, span: Span) {
+        let mut inner = String::new();
+        loop {
+            match self.bump() {
+                Some('\'') => break,
+                Some('\\') => {
+                    inner.push('\\');
+                    if let Some(c) = self.bump() {
+                        inner.push(c);
+                    }
+                }
+                Some('\n') | None => {
+                    self.literal_error(span, "unterminated character literal".into());
+                    return;
+                }
+                Some(c) => inner.push(c),
+            }
+        }
+        match parse_char(&inner) {
+            Ok(c) => self.tokens.push(Token {
+                kind: TokenKind::Int(c as i64),
+                span,
+                text: format!("'{}'", inner),
+            }),
+            Err(err) => self.literal_error(span, err),
+        }
+    }
+    fn string(&mut self, span: Span) {
+        let mut text = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => break,
+                Some('\\') => match self.bump() {
+                    Some('n') => text.push('\n'),
+                    Some('t') => text.push('\t'),
+                    Some(c) => text.push(c),
+                    None => {
+                        self.literal_error(span, "unterminated string".into());
+                        return;
+                    }
+                },
+                Some(c) => text.push(c),
+                None => {
+                    self.literal_error(span, "unterminated string".into());
+                    return;
+                }
+            }
+        }
+        self.push(TokenKind::Str(text), span);
+    }
+}
+
+/// Tracks whether a text scan is inside a `"string"` or `'c'` literal.
+pub(crate) fn toggle_quote(quote: &mut Option<char>, c: char) {
+    match *quote {
+        None if c == '"' || c == '\'' => *quote = Some(c),
+        Some(open) if open == c => *quote = None,
+        _ => {}
+    }
+}
+
+/// Splits source text into tokens. Problems are collected as diagnostics and
+/// the offending characters skipped, so lexing always reaches the end.
+///
+/// `// ...`, `;; ...` and `/* ... */` are comments and produce no tokens,
+/// except `/// ...` which becomes a `Doc` token.
+pub fn lex(src: &str) -> (Vec<Token>, Vec<Diagnostic>) {
+    lex_source(src, false)
+}
+
+/// Like `lex`, but every comment is kept as a `Comment` token so the source
+/// can be written back out.
+pub fn lex_with_comments(src: &str) -> (Vec<Token>, Vec<Diagnostic>) {
+    lex_source(src, true)
+}
+
+fn lex_source(src: &str, keep_comments: bool) -> (Vec<Token>, Vec<Diagnostic>) {
+    let mut lexer = Lexer {
+        chars: src.chars().peekable(),
+        line: 1,
+        col: 1,
+        tokens: vec![],
+        diagnostics: vec![],
+        keep_comments,
+    };
+    loop {
+        let span = Span {
+            line: lexer.line,
+            col: lexer.col,
+        };
+        let Some(c) = lexer.bump() else {
+            break;
+        };
+        match c {
+            '\n' => lexer.push(TokenKind::Newline, span),
+            c if c.is_whitespace() => {}
+            ':' => lexer.push(TokenKind::Colon, span),
+            ',' => lexer.push(TokenKind::Comma, span),
+            ';' if lexer.peek() == Some(';') => {
+                let text = lexer.line_comment();
+                lexer.comment(format!(";{}", text.trim_end()), span);
+            }
+            '/' if lexer.peek() == Some('/') => {
+                lexer.bump();
+                if lexer.peek() == Some('/') {
+                    lexer.bump();
+                    let text = lexer.line_comment();
+                    let text = text.strip_prefix(' ').unwrap_or(&text).trim_end();
+                    lexer.push(TokenKind::Doc(text.to_string()), span);
+                } else {
+                    let text = lexer.line_comment();
+                    lexer.comment(format!("//{}", text.trim_end()), span);
+                }
+            }
+            '/' if lexer.peek() == Some('*') => {
+                lexer.bump();
+                lexer.block_comment(span);
+            }
+            ';' => lexer.push(TokenKind::Semicolon, span),
+            '"' => lexer.string(span),
+            '\'' => lexer.char(span),
+            '-' if lexer.peek().is_some_and(|c| c.is_ascii_digit()) => {
+                lexer.number(c, span);
+            }
+            c if c.is_ascii_digit() => lexer.number(c, span),
+            c if c.is_alphabetic() || c == '_' => {
+                let word = lexer.word(c);
+                lexer.push(TokenKind::Word(word), span);
+            }
+            c => lexer.error(span, format!("unexpected character `{}`", c)),
+        }
+    }
+    (lexer.tokens, lexer.diagnostics)
+}
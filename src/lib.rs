@@ -1,5 +1,18 @@
+pub mod checker;
+pub mod control;
 pub mod cpu;
+pub mod diagnostic;
+pub mod disasm;
 pub mod error;
+pub mod formatter;
 pub mod insts;
+mod json;
+pub mod lexer;
+pub mod limits;
+pub mod module;
+pub mod parser;
+pub mod preprocessor;
+pub mod sandbox;
+pub mod snapshot;
 pub mod tokenizer;
 pub mod venobjects;
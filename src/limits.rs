@@ -0,0 +1,149 @@
+use crate::{
+    cpu::CPU,
+    error::{Error, RuntimeError},
+    venobjects::VenObjects,
+};
+use std::{
+    mem::size_of,
+    time::{Duration, Instant},
+};
+
+/// Resource budgets for a single run. `None` means unlimited.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Limits {
+    /// Instructions executed, loop iterations included.
+    pub max_instructions: Option<u64>,
+    /// Nesting of `run` calls, also set by `#stack N`.
+    pub max_call_depth: Option<usize>,
+    /// Wall-clock time since the run started.
+    pub max_duration: Option<Duration>,
+    /// Bytes held by the register file and the strings stored in it.
+    pub max_memory: Option<usize>,
+}
+
+/// The nesting of `run` the command line allows unless told otherwise, so
+/// that runaway recursion stops with an error instead of exhausting memory.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 100_000;
+
+/// How often, in instructions, the clock is read.
+const CLOCK_INTERVAL: u64 = 1024;
+
+impl CPU {
+    /// Clears the instruction count, the clock and the output count before a
+    /// new run.
+    pub fn reset_budget(&mut self) {
+        self.executed = 0;
+        self.output_bytes = 0;
+        self.started = Some(Instant::now());
+    }
+    /// Accounts for one executed instruction.
+    pub(crate) fn tick(&mut self) -> Result<(), RuntimeError> {
+        self.executed += 1;
+        if let Some(max) = self.limits.max_instructions {
+            if self.executed > max {
+                return Err(Error::INSTRUCTION_LIMIT
+                    .with(format!("more than {} instructions executed", max)));
+            }
+        }
+        if let Some(max) = self.limits.max_duration {
+            if self.executed.is_multiple_of(CLOCK_INTERVAL) {
+                let started = *self.started.get_or_insert_with(Instant::now);
+                if started.elapsed() > max {
+                    return Err(Error::TIME_LIMIT
+                        .with(format!("running for more than {} ms", max.as_millis())));
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Bytes currently held by registers and the accumulator.
+    pub fn memory_used(&self) -> usize {
+        (self.registers.len() + 1) * size_of::<VenObjects>() + self.heap_bytes
+    }
+    /// Records that `old` was replaced by `new` and checks the memory budget.
+    pub(crate) fn account(
+        &mut self,
+        old: &VenObjects,
+        new: &VenObjects,
+    ) -> Result<(), RuntimeError> {
+        self.heap_bytes = (self.heap_bytes + new.heap_size()).saturating_sub(old.heap_size());
+        match self.limits.max_memory {
+            Some(max) if self.memory_used() > max => Err(Error::MEMORY_LIMIT.with(format!(
+                "{} bytes in use, the limit is {}",
+                self.memory_used(),
+                max
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `src` under `limits` and returns the kind of error it stops with.
+    fn stop(src: &str, limits: Limits) -> Option<Error> {
+        let mut cpu = CPU::new();
+        cpu.init(10).unwrap();
+        cpu.quiet = true;
+        cpu.limits = limits;
+        cpu.parse_instructions(src.into())
+            .unwrap_or_else(|errors| panic!("{:?}", errors));
+        cpu.exec(None).err().map(|err| err.kind)
+    }
+
+    #[test]
+    fn instruction_limit_stops_an_endless_loop() {
+        let limits = Limits {
+            max_instructions: Some(1000),
+            ..Limits::default()
+        };
+        let src = "block main:\nwhile true\nendwhile\nend\n";
+        assert_eq!(stop(src, limits), Some(Error::INSTRUCTION_LIMIT));
+    }
+
+    #[test]
+    fn call_depth_limit_stops_recursion() {
+        let src = "block main:\nrun main\nend\n";
+        let limits = Limits {
+            max_call_depth: Some(50),
+            ..Limits::default()
+        };
+        assert_eq!(stop(src, limits), Some(Error::STACK_OVERFLOW));
+        let src = "#stack 5!\nblock main:\nrun main\nend\n";
+        assert_eq!(stop(src, Limits::default()), Some(Error::STACK_OVERFLOW));
+    }
+
+    #[test]
+    fn time_limit_stops_an_endless_loop() {
+        let limits = Limits {
+            max_duration: Some(Duration::from_millis(20)),
+            ..Limits::default()
+        };
+        let src = "block main:\nwhile true\nendwhile\nend\n";
+        assert_eq!(stop(src, limits), Some(Error::TIME_LIMIT));
+    }
+
+    #[test]
+    fn memory_limit_stops_large_strings() {
+        let limits = Limits {
+            max_memory: Some(4096),
+            ..Limits::default()
+        };
+        let src = format!("block main:\nmov r1, \"{}\"\nend\n", "x".repeat(8192));
+        assert_eq!(stop(&src, limits), Some(Error::MEMORY_LIMIT));
+    }
+
+    #[test]
+    fn programs_within_their_limits_finish() {
+        let limits = Limits {
+            max_instructions: Some(100),
+            max_call_depth: Some(2),
+            max_duration: Some(Duration::from_secs(60)),
+            max_memory: Some(1 << 20),
+        };
+        let src = "block helper:\nmov r1, 1\nend\nblock main:\nrun helper\nrun helper\nend\n";
+        assert_eq!(stop(src, limits), None);
+    }
+}
@@ -1,26 +1,726 @@
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::env;
 use std::fs;
+use std::io::{self, BufRead, Read, Write};
 use std::process::exit;
-use veneno_engine::cpu;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use veneno_engine::control::Status;
+use veneno_engine::cpu::{CPU, MAX_REGISTERS};
+use veneno_engine::diagnostic::Diagnostic;
+use veneno_engine::disasm::disassemble_at;
+use veneno_engine::error::{json_errors, json_summary, set_json_errors, Error, RuntimeError};
+use veneno_engine::formatter::format_source;
+use veneno_engine::insts::Instructions;
+use veneno_engine::limits::DEFAULT_MAX_CALL_DEPTH;
+use veneno_engine::sandbox::SandboxPolicy;
 use veneno_engine::venobjects::VenObjects;
 
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const USAGE: &str = "\
+Usage: veneno-engine [command] [options] <script | - | -e code> [args...]
+
+Commands:
+  run       Run a program (the default)
+  check     Report problems without running anything
+  fmt       Rewrite a file in canonical form
+  disasm    Print the loaded blocks one instruction per line
+  repl      Read and run statements interactively
+  debug     Step through a program
+  bench     Run a program repeatedly and report timings
+
+Options:
+  -e <code>                Run <code> instead of a file; statements outside of
+                           a block are wrapped in `block main:`
+  -                        Read the program from stdin
+  -D <name>[=value]        Define a preprocessor symbol
+  --entry <block>          Start from <block> instead of `main`
+  --registers <n>          Size of the register file, overriding `#rN`
+  --grow-registers         Grow the register file on demand
+  --trace                  Print every instruction to stderr as it runs
+  --max-instructions <n>   Stop after <n> instructions
+  --max-depth <n>          Limit the nesting of `run`, overriding `#stack`
+                           (default 100000)
+  --timeout <ms>           Stop after <ms> milliseconds
+  --max-memory <bytes>     Limit memory held by registers
+  --sandbox                Deny file and environment access, cap output
+  --max-output <bytes>     Limit how much the program may print
+  --check                  With fmt: only report whether the file would change
+  --iterations <n>         With bench: number of runs (default 10)
+  --error-format <format>  `text` (the default) or `json` for a one-line JSON
+                           summary of each error on stderr
+  -h, --help               Print this help
+  -V, --version            Print the version
+
+Arguments after the script are passed to it, see `argc` and `argv`.
+
+Exit status:
+  0    success, or the code given to `halt`
+  1    check or fmt --check found problems
+  2    invalid command line
+  70   parse error            71   cannot read or write a file
+  72   import cycle           73   unknown directive
+  74   invalid directive      75   invalid macro
+  76   macro expansion        77   invalid literal
+  78   no entry point         79   invalid snapshot
+  80   invalid register       81   invalid block syntax
+  82   invalid int operands   83   invalid value for mov
+  84   invalid run syntax     85   invalid times syntax
+  86   invalid for syntax     87   invalid bool operands
+  88   invalid declaration    89   cannot declare acc
+  90   undeclared alias       91   uninitialized register
+  92   type mismatch          93   read-only register
+  100  stack overflow         101  instruction limit
+  102  time limit             103  memory limit
+  104  output limit           105  sandbox violation
+  106  cancelled";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Command {
+    Run,
+    Check,
+    Fmt,
+    Disasm,
+    Repl,
+    Debug,
+    Bench,
+}
+
+/// Where the program comes from.
+enum Source {
+    File(String),
+    Stdin,
+    Inline(String),
+}
+
+struct Options {
+    command: Command,
+    source: Option<Source>,
+    check: bool,
+    registers: Option<usize>,
+    max_depth: Option<usize>,
+    iterations: usize,
+}
+
 fn main() {
-    let mut arg = env::args().skip(1);
-    let mut cpu = cpu::CPU {
-        registers: vec![],
-        acc: VenObjects::Empty,
-        blocks: HashMap::new(),
-        aliases: HashMap::new(),
-        tokens: vec![],
+    let mut cpu = CPU::new();
+    cpu.init(100).expect("no limits are set yet");
+    cpu.limits.max_call_depth = Some(DEFAULT_MAX_CALL_DEPTH);
+    let options = parse_args(&mut cpu, env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("error: {}\n\nRun `veneno-engine --help` for usage.", err);
+        exit(2);
+    });
+    if options.command == Command::Repl {
+        configure(&mut cpu, &options);
+        repl(cpu);
+    }
+    let Some(source) = &options.source else {
+        eprintln!("error: no program given\n\n{}", USAGE);
+        exit(2);
     };
-    cpu.init(100);
-    let data = fs::read_to_string(arg.next().unwrap().clone());
-    if let Ok(data) = data {
-        cpu.parse_instructions(data);
-        cpu.exec(None);
+    if options.command == Command::Fmt {
+        fmt(source, options.check);
+    }
+    if let Err(diagnostics) = load(&mut cpu, source) {
+        report(&diagnostics);
+        exit(if options.command == Command::Check {
+            1
+        } else {
+            load_error(&diagnostics).exit_code()
+        });
+    }
+    configure(&mut cpu, &options);
+    match options.command {
+        Command::Disasm => print!("{}", cpu.disassemble()),
+        Command::Check => {
+            let errors = report(&cpu.check());
+            exit(if errors > 0 { 1 } else { 0 });
+        }
+        Command::Debug => debug(cpu),
+        Command::Bench => bench(cpu, options.iterations),
+        _ => {
+            let status = cpu.exec(None);
+            let _ = io::stdout().flush();
+            match status {
+                Ok(status) => exit(status.code.unwrap_or(0)),
+                Err(err) => fail(&err),
+            }
+        }
+    }
+}
+
+fn parse_args<I: Iterator<Item = String>>(cpu: &mut CPU, args: I) -> Result<Options, String> {
+    let mut args = args.peekable();
+    let mut options = Options {
+        command: Command::Run,
+        source: None,
+        check: false,
+        registers: None,
+        max_depth: None,
+        iterations: 10,
+    };
+    let mut command_given = false;
+    let mut max_output = None;
+    while let Some(arg) = args.next() {
+        if options.source.is_some() {
+            // everything after the script is passed on to it
+            cpu.args.push(arg);
+            continue;
+        }
+        if !command_given {
+            if let Some(command) = command(&arg) {
+                // the command may follow options, as in `--trace run x.ben`
+                options.command = command;
+                command_given = true;
+                continue;
+            }
+        }
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0);
+            }
+            "-V" | "--version" => {
+                println!("veneno-engine {}", VERSION);
+                exit(0);
+            }
+            "-" => options.source = Some(Source::Stdin),
+            "-e" => options.source = Some(Source::Inline(value(&arg, args.next())?)),
+            "--check" => options.check = true,
+            "--trace" => cpu.trace = true,
+            "--grow-registers" => cpu.grow_registers = true,
+            "--sandbox" => cpu.sandbox = SandboxPolicy::untrusted(),
+            "--entry" => cpu.entry = value(&arg, args.next())?,
+            "--registers" => {
+                let registers = number(&arg, args.next())?;
+                if registers > MAX_REGISTERS {
+                    return Err(format!("--registers is at most {}", MAX_REGISTERS));
+                }
+                options.registers = Some(registers);
+            }
+            "--max-depth" => options.max_depth = Some(number(&arg, args.next())?),
+            "--iterations" => options.iterations = number(&arg, args.next())?,
+            "--error-format" => set_error_format(&value(&arg, args.next())?)?,
+            _ if arg.starts_with("--error-format=") => {
+                set_error_format(&arg["--error-format=".len()..])?
+            }
+            "--max-instructions" => cpu.limits.max_instructions = Some(number(&arg, args.next())?),
+            "--timeout" => {
+                cpu.limits.max_duration = Some(Duration::from_millis(number(&arg, args.next())?))
+            }
+            "--max-memory" => cpu.limits.max_memory = Some(number(&arg, args.next())?),
+            "--max-output" => max_output = Some(number(&arg, args.next())?),
+            _ if arg.starts_with("-D") => {
+                let define = match &arg[2..] {
+                    "" => value(&arg, args.next())?,
+                    define => define.to_string(),
+                };
+                let (name, value) = define.split_once('=').unwrap_or((&define, "1"));
+                cpu.defines.insert(name.to_string(), value.to_string());
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{}`", arg)),
+            _ => options.source = Some(Source::File(arg)),
+        }
+    }
+    // applied last so that a later `--sandbox` does not reset it
+    if max_output.is_some() {
+        cpu.sandbox.max_output = max_output;
+    }
+    Ok(options)
+}
+
+fn command(name: &str) -> Option<Command> {
+    match name {
+        "run" => Some(Command::Run),
+        "check" => Some(Command::Check),
+        "fmt" => Some(Command::Fmt),
+        "disasm" => Some(Command::Disasm),
+        "repl" => Some(Command::Repl),
+        "debug" => Some(Command::Debug),
+        "bench" => Some(Command::Bench),
+        _ => None,
+    }
+}
+
+fn value(flag: &str, value: Option<String>) -> Result<String, String> {
+    value.ok_or_else(|| format!("{} expects a value", flag))
+}
+
+/// Parses the value of a numeric flag.
+fn number<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    value
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| format!("{} expects a number", flag))
+}
+
+fn set_error_format(format: &str) -> Result<(), String> {
+    match format {
+        "text" => set_json_errors(false),
+        "json" => set_json_errors(true),
+        other => return Err(format!("unknown error format `{}`", other)),
+    }
+    Ok(())
+}
+
+/// Applies the options that must win over directives in the source.
+fn configure(cpu: &mut CPU, options: &Options) {
+    if let Some(registers) = options.registers {
+        if let Err(err) = cpu.init(registers) {
+            fail(&err);
+        }
+    }
+    if options.max_depth.is_some() {
+        cpu.limits.max_call_depth = options.max_depth;
+    }
+}
+
+fn read_source(source: &Source) -> Result<String, String> {
+    match source {
+        Source::File(path) => {
+            fs::read_to_string(path).map_err(|err| format!("cannot read {}: {}", path, err))
+        }
+        Source::Stdin => {
+            let mut src = String::new();
+            io::stdin()
+                .read_to_string(&mut src)
+                .map_err(|err| format!("cannot read stdin: {}", err))?;
+            Ok(src)
+        }
+        Source::Inline(code) => Ok(wrap_inline(code)),
+    }
+}
+
+/// Lets `-e` take bare statements by wrapping them in `block main:`.
+fn wrap_inline(code: &str) -> String {
+    let has_blocks = code
+        .lines()
+        .any(|line| line.trim_start().starts_with("block "));
+    if has_blocks {
+        code.to_string()
     } else {
-        eprintln!("Cannot read from file: {:?}", data);
-        exit(69);
+        format!("block main:\n{}\nend\n", code)
+    }
+}
+
+fn load(cpu: &mut CPU, source: &Source) -> Result<(), Vec<Diagnostic>> {
+    match source {
+        Source::File(path) => cpu.load_file(path),
+        source => {
+            let src = read_source(source).map_err(|err| {
+                vec![Diagnostic::error(None, err).with_kind(Error::CANNOT_READ_FILE)]
+            })?;
+            let wrapped = matches!(source, Source::Inline(code) if *code != src);
+            cpu.parse_instructions(src)
+                .map_err(|diagnostics| unwrap_lines(diagnostics, wrapped))
+        }
+    }
+}
+
+/// Moves diagnostics for code that was wrapped in a one-line block header
+/// back to the lines the user wrote.
+fn unwrap_lines(diagnostics: Vec<Diagnostic>, wrapped: bool) -> Vec<Diagnostic> {
+    if !wrapped {
+        return diagnostics;
+    }
+    diagnostics
+        .into_iter()
+        .map(|mut diagnostic| {
+            if let Some(span) = &mut diagnostic.span {
+                span.line = span.line.saturating_sub(1).max(1);
+            }
+            diagnostic
+        })
+        .collect()
+}
+
+/// Prints `source` in canonical form: files are rewritten in place, stdin
+/// and `-e` code go to stdout. With `check` nothing is written and the
+/// process exits with 1 if the source is not formatted.
+fn fmt(source: &Source, check: bool) -> ! {
+    let src = read_source(source).unwrap_or_else(|err| fail(&Error::CANNOT_READ_FILE.with(err)));
+    let formatted = match format_source(&src) {
+        Ok(formatted) => formatted,
+        Err(diagnostics) => {
+            report(&diagnostics);
+            exit(load_error(&diagnostics).exit_code());
+        }
+    };
+    let Source::File(path) = source else {
+        if check {
+            exit(if formatted == src { 0 } else { 1 });
+        }
+        print!("{}", formatted);
+        exit(0);
+    };
+    if formatted == src {
+        exit(0);
+    }
+    if check {
+        eprintln!("would reformat {}", path);
+        exit(1);
+    }
+    if let Err(err) = fs::write(path, formatted) {
+        fail(&Error::CANNOT_READ_FILE.with(format!("cannot write {}: {}", path, err)));
+    }
+    exit(0);
+}
+
+/// How much a line opens or closes nested constructs, so the REPL knows
+/// when a multi-line statement is complete.
+fn nesting(line: &str) -> i32 {
+    match line.split_whitespace().next().unwrap_or_default() {
+        "block" | "macro" | "if" | "while" | "times" | "for" => 1,
+        "end" | "endmacro" | "endif" | "endwhile" | "endtimes" | "endfor" => -1,
+        _ => 0,
+    }
+}
+
+fn repl(mut cpu: CPU) -> ! {
+    println!("veneno-engine {}, :help for commands", VERSION);
+    let stdin = io::stdin();
+    let mut pending = String::new();
+    let mut depth = 0;
+    loop {
+        print!("{}", if pending.is_empty() { "> " } else { "... " });
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => {
+                println!();
+                exit(0);
+            }
+            Ok(_) => {}
+        }
+        let trimmed = line.trim();
+        if pending.is_empty() && trimmed.starts_with(':') {
+            repl_command(&cpu, trimmed);
+            continue;
+        }
+        pending.push_str(&line);
+        depth += nesting(trimmed);
+        if depth > 0 {
+            continue;
+        }
+        depth = 0;
+        let src = std::mem::take(&mut pending);
+        if src.trim_start().starts_with("block ") {
+            if let Err(diagnostics) = cpu.parse_instructions(src) {
+                report(&diagnostics);
+            }
+            continue;
+        }
+        let src = format!("block __repl__:\n{}\nend\n", src);
+        let body = match cpu.parse_source(&src, None) {
+            Ok(mut program) if !program.blocks.is_empty() => program.blocks.remove(0).body,
+            Ok(_) => continue,
+            Err(diagnostics) => {
+                report(&unwrap_lines(diagnostics, true));
+                continue;
+            }
+        };
+        cpu.reset_budget();
+        match cpu.exec(Some(&body)) {
+            Ok(status) => {
+                if let Some(code) = status.code {
+                    exit(code);
+                }
+            }
+            Err(err) => {
+                print_error(&err);
+                cpu.unwind();
+            }
+        }
+    }
+}
+
+fn repl_command(cpu: &CPU, command: &str) {
+    match command {
+        ":q" | ":quit" => exit(0),
+        ":blocks" => {
+            let mut names: Vec<&String> = cpu.blocks.keys().collect();
+            names.sort();
+            for name in names {
+                match cpu.docs.get(name).and_then(|doc| doc.lines().next()) {
+                    Some(doc) => println!("{:<16} {}", name, doc),
+                    None => println!("{}", name),
+                }
+            }
+        }
+        ":regs" => print_registers(cpu),
+        ":help" => println!(
+            "Statements run as soon as they are complete; `block` definitions are kept.\n\
+             :blocks  list blocks with their documentation\n\
+             :regs    show acc and every register that holds a value\n\
+             :quit    leave"
+        ),
+        other => eprintln!("unknown command `{}`, try :help", other),
+    }
+}
+
+fn print_registers(cpu: &CPU) {
+    println!("acc = {}", show(&cpu.acc));
+    for (rid, value) in cpu.registers.iter().enumerate() {
+        if *value != VenObjects::Empty {
+            println!("r{} = {}", rid, show(value));
+        }
+    }
+}
+
+fn show(value: &VenObjects) -> String {
+    match value {
+        VenObjects::Str(stri) => format!("{:?}", stri),
+        value => value.to_string(),
+    }
+}
+
+/// The instruction the innermost frame executes next, skipping separators.
+fn current(cpu: &CPU) -> Option<String> {
+    let frame = cpu.frames.last()?;
+    let block = frame.block.as_deref().unwrap_or("<top>");
+    let line = match (frame.pc..frame.body.len()).find(|&i| frame.body[i] != Instructions::EOL) {
+        Some(pc) => disassemble_at(&frame.body, pc).0,
+        None => format!("{:04}  end", frame.body.len()),
+    };
+    Some(format!("[{}] {}", block, line))
+}
+
+fn debug(mut cpu: CPU) -> ! {
+    if let Err(err) = cpu.start() {
+        fail(&err);
+    }
+    println!("Debugging, `help` for commands");
+    let stdin = io::stdin();
+    let mut breakpoints: HashSet<String> = HashSet::new();
+    let mut moved = true;
+    loop {
+        if moved {
+            if let Some(next) = current(&cpu) {
+                println!("{}", next);
+            }
+        }
+        moved = false;
+        print!("(debug) ");
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        if matches!(stdin.lock().read_line(&mut line), Ok(0) | Err(_)) {
+            exit(0);
+        }
+        let mut words = line.split_whitespace();
+        let steps = match words.next().unwrap_or("step") {
+            "s" | "step" => words.next().and_then(|n| n.parse().ok()).unwrap_or(1),
+            "c" | "continue" => u64::MAX,
+            "b" | "break" => {
+                match words.next() {
+                    Some(block) => {
+                        breakpoints.insert(block.to_string());
+                    }
+                    None => eprintln!("break expects a block name"),
+                }
+                continue;
+            }
+            "r" | "regs" => {
+                print_registers(&cpu);
+                continue;
+            }
+            "bt" | "stack" => {
+                for frame in cpu.frames.iter().rev() {
+                    let block = frame.block.as_deref().unwrap_or("<top>");
+                    println!("  {} at {:04}", block, frame.pc);
+                }
+                continue;
+            }
+            "q" | "quit" => exit(0),
+            "h" | "help" => {
+                println!(
+                    "step [n]      execute n instructions (default 1)\n\
+                     continue      run until a breakpoint or the end\n\
+                     break <block> stop whenever <block> is entered\n\
+                     regs          show acc and the registers in use\n\
+                     stack         show the call stack\n\
+                     quit          leave"
+                );
+                continue;
+            }
+            other => {
+                eprintln!("unknown command `{}`, try help", other);
+                continue;
+            }
+        };
+        moved = true;
+        for _ in 0..steps {
+            let depth = cpu.frames.len();
+            match cpu.run_for(1) {
+                Ok(Status::Finished) => {
+                    let _ = io::stdout().flush();
+                    println!("program finished, acc = {}", show(&cpu.acc));
+                    exit(cpu.exit_code.unwrap_or(0));
+                }
+                Ok(_) => {}
+                Err(err) => fail(&err),
+            }
+            let entered = cpu.frames.len() > depth
+                && cpu
+                    .frames
+                    .last()
+                    .and_then(|frame| frame.block.as_ref())
+                    .is_some_and(|block| breakpoints.contains(block));
+            if entered {
+                println!("breakpoint");
+                break;
+            }
+        }
+    }
+}
+
+/// Runs the loaded program `iterations` times with its output discarded and
+/// prints timing statistics.
+fn bench(mut cpu: CPU, iterations: usize) -> ! {
+    cpu.quiet = true;
+    let mut times = vec![];
+    let mut executed = 0;
+    for _ in 0..iterations.max(1) {
+        let mut run = cpu.clone();
+        let started = Instant::now();
+        if let Err(err) = run.exec(None) {
+            fail(&err);
+        }
+        times.push(started.elapsed());
+        executed = run.executed;
+    }
+    let total: Duration = times.iter().sum();
+    let mean = total / times.len() as u32;
+    let min = times.iter().min().copied().unwrap_or_default();
+    let max = times.iter().max().copied().unwrap_or_default();
+    println!("runs:         {}", times.len());
+    println!("instructions: {} per run", executed);
+    println!(
+        "time:         mean {:?}, min {:?}, max {:?}",
+        mean, min, max
+    );
+    if !mean.is_zero() {
+        println!(
+            "throughput:   {:.0} instructions/s",
+            executed as f64 / mean.as_secs_f64()
+        );
+    }
+    exit(0);
+}
+
+fn print_error(err: &RuntimeError) {
+    if json_errors() {
+        eprintln!("{}", err.to_json());
+    } else {
+        eprintln!("{}", err);
+    }
+}
+
+fn fail(err: &RuntimeError) -> ! {
+    print_error(err);
+    exit(err.exit_code());
+}
+
+/// The error a failed load exits with: the first error more specific than a
+/// parse error, since a bad literal or directive usually causes parse errors
+/// of its own.
+fn load_error(diagnostics: &[Diagnostic]) -> Error {
+    diagnostics
+        .iter()
+        .filter(|d| d.is_error())
+        .map(|d| d.kind)
+        .find(|kind| *kind != Error::PARSE_ERROR)
+        .unwrap_or(Error::PARSE_ERROR)
+}
+
+/// Prints diagnostics followed by a summary line and returns the number of
+/// errors among them. With `--error-format=json` a single JSON summary is
+/// printed instead, and only when there are errors.
+fn report(diagnostics: &[Diagnostic]) -> usize {
+    let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+    let summary = format!(
+        "{} error(s), {} warning(s)",
+        errors,
+        diagnostics.len() - errors
+    );
+    if json_errors() {
+        if errors > 0 {
+            let kind = load_error(diagnostics);
+            eprintln!("{}", json_summary(kind, &summary, diagnostics));
+        }
+        return errors;
+    }
+    for diagnostic in diagnostics.iter() {
+        eprintln!("{}", diagnostic);
+    }
+    eprintln!("{}", summary);
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> (CPU, Result<Options, String>) {
+        let mut cpu = CPU::new();
+        let options = parse_args(&mut cpu, args.split_whitespace().map(str::to_string));
+        (cpu, options)
+    }
+
+    fn error(args: &str) -> String {
+        parse(args).1.err().unwrap_or_default()
+    }
+
+    #[test]
+    fn the_command_may_follow_options() {
+        let (cpu, options) = parse("--trace check x.ben");
+        let options = options.unwrap();
+        assert!(options.command == Command::Check);
+        assert!(cpu.trace);
+        assert!(matches!(&options.source, Some(Source::File(f)) if f == "x.ben"));
+    }
+
+    #[test]
+    fn arguments_after_the_script_go_to_the_script() {
+        let (cpu, options) = parse("run x.ben --trace check -D");
+        let options = options.unwrap();
+        assert!(options.command == Command::Run);
+        assert!(!cpu.trace);
+        assert_eq!(cpu.args, ["--trace", "check", "-D"]);
+        // only the first command word is a command
+        let options = parse("run check").1.unwrap();
+        assert!(matches!(&options.source, Some(Source::File(f)) if f == "check"));
+    }
+
+    #[test]
+    fn sources_and_defines() {
+        let (cpu, options) = parse("-DX=2 -D Y -e mov");
+        assert!(matches!(options.unwrap().source, Some(Source::Inline(code)) if code == "mov"));
+        assert_eq!(cpu.defines["X"], "2");
+        assert_eq!(cpu.defines["Y"], "1");
+        assert!(matches!(parse("-").1.unwrap().source, Some(Source::Stdin)));
+        assert!(parse("fmt").1.unwrap().source.is_none());
+    }
+
+    #[test]
+    fn limits_and_sandbox_flags() {
+        let (cpu, options) =
+            parse("--max-output 10 --sandbox --max-instructions 5 --timeout 20 --max-depth 3 x");
+        assert_eq!(options.unwrap().max_depth, Some(3));
+        assert_eq!(cpu.sandbox.max_output, Some(10));
+        assert!(!cpu.sandbox.allows(veneno_engine::sandbox::Family::FileIo));
+        assert_eq!(cpu.limits.max_instructions, Some(5));
+        assert_eq!(cpu.limits.max_duration, Some(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn rejects_bad_options() {
+        assert_eq!(error("--frobnicate x.ben"), "unknown option `--frobnicate`");
+        assert_eq!(error("--entry"), "--entry expects a value");
+        assert_eq!(error("--max-depth lots"), "--max-depth expects a number");
+        assert_eq!(
+            error(&format!("--registers {}", MAX_REGISTERS + 1)),
+            format!("--registers is at most {}", MAX_REGISTERS)
+        );
+        assert_eq!(error("--error-format=xml"), "unknown error format `xml`");
     }
 }
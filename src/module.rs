@@ -0,0 +1,264 @@
+use crate::{
+    cpu::CPU, diagnostic::Diagnostic, error::Error, insts::Instructions, parser::Block,
+    sandbox::Family,
+};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+impl CPU {
+    /// Loads the program at `path` together with every file it imports.
+    ///
+    /// Imported files are resolved relative to the file that imports them and
+    /// their blocks are registered under the file's stem, so `block sqrt:`
+    /// inside `math.ben` becomes `math::sqrt`. Problems in any of the files
+    /// are collected and returned together.
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Vec<Diagnostic>> {
+        let mut stack = vec![];
+        let mut loaded = HashSet::new();
+        let mut diagnostics = vec![];
+        let tokens = self.import_file(
+            path.as_ref(),
+            None,
+            &mut stack,
+            &mut loaded,
+            &mut diagnostics,
+        );
+        if diagnostics.iter().any(Diagnostic::is_error) {
+            return Err(diagnostics);
+        }
+        self.tokens = tokens;
+        Ok(())
+    }
+    fn import_file(
+        &mut self,
+        path: &Path,
+        namespace: Option<String>,
+        stack: &mut Vec<PathBuf>,
+        loaded: &mut HashSet<PathBuf>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Vec<Instructions> {
+        let file = path.display().to_string();
+        let in_file = |diagnostic: Diagnostic| Diagnostic {
+            file: diagnostic.file.clone().or_else(|| Some(file.clone())),
+            ..diagnostic
+        };
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if stack.contains(&canonical) {
+            let mut chain: Vec<String> = stack
+                .iter()
+                .skip_while(|p| **p != canonical)
+                .map(|p| p.display().to_string())
+                .collect();
+            chain.push(canonical.display().to_string());
+            diagnostics.push(
+                Diagnostic::error(None, format!("import cycle: {}", chain.join(" -> ")))
+                    .with_kind(Error::IMPORT_CYCLE),
+            );
+            return vec![];
+        }
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(err) => {
+                diagnostics.push(in_file(
+                    Diagnostic::error(None, format!("cannot read file: {}", err))
+                        .with_kind(Error::CANNOT_READ_FILE),
+                ));
+                return vec![];
+            }
+        };
+        stack.push(canonical.clone());
+        let mut program = match self.parse_source(&data, Some(path)) {
+            Ok(program) => program,
+            Err(errors) => {
+                diagnostics.extend(errors.into_iter().map(in_file));
+                stack.pop();
+                return vec![];
+            }
+        };
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        for import in program.imports.iter() {
+            if !self.sandbox.allows(Family::FileIo) {
+                diagnostics.push(in_file(
+                    Diagnostic::error(
+                        Some(import.span),
+                        format!(
+                            "`import \"{}\"` is not allowed by the sandbox policy",
+                            import.path
+                        ),
+                    )
+                    .with_kind(Error::SANDBOX_VIOLATION),
+                ));
+                continue;
+            }
+            let import_path = dir.join(&import.path);
+            let import_canonical =
+                fs::canonicalize(&import_path).unwrap_or_else(|_| import_path.clone());
+            if loaded.contains(&import_canonical) {
+                continue;
+            }
+            let stem = import_path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if stem.is_empty() {
+                diagnostics.push(in_file(Diagnostic::error(
+                    Some(import.span),
+                    format!("cannot import `{}`", import.path),
+                )));
+                continue;
+            }
+            self.import_file(&import_path, Some(stem), stack, loaded, diagnostics);
+        }
+        for global in program.globals.iter() {
+            if let Some(&register) = self.aliases.get(&global.name) {
+                if register != global.register {
+                    diagnostics.push(in_file(Diagnostic::error(
+                        Some(global.span),
+                        format!("global `{}` is already bound to r{}", global.name, register),
+                    )));
+                }
+            }
+            self.aliases.insert(global.name.clone(), global.register);
+        }
+        if let Some(namespace) = namespace {
+            qualify_blocks(&mut program.blocks, &namespace);
+        }
+        stack.pop();
+        loaded.insert(canonical);
+        match self.register_blocks(program.blocks) {
+            Ok(tokens) => {
+                for token in tokens.iter() {
+                    if let Instructions::BLOCK(name, _) = token {
+                        self.files.insert(name.clone(), file.clone());
+                    }
+                }
+                tokens
+            }
+            Err(errors) => {
+                diagnostics.extend(errors.into_iter().map(in_file));
+                vec![]
+            }
+        }
+    }
+}
+
+/// Prefixes every block defined in a module with `namespace::` and rewrites
+/// `run` targets that refer to blocks of the same module.
+fn qualify_blocks(blocks: &mut [Block], namespace: &str) {
+    let local: HashSet<String> = blocks.iter().map(|block| block.name.clone()).collect();
+    for block in blocks.iter_mut() {
+        block.name = format!("{}::{}", namespace, block.name);
+        let body = &mut block.body;
+        for i in 1..body.len() {
+            if body[i - 1] != Instructions::RUN {
+                continue;
+            }
+            if let Instructions::IDENT(target) = &body[i] {
+                if local.contains(target) {
+                    body[i] = Instructions::IDENT(format!("{}::{}", namespace, target));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `files` to a fresh directory named after `test` and returns it.
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("veneno-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (name, src) in files {
+            fs::write(dir.join(name), src).unwrap();
+        }
+        dir
+    }
+
+    fn load(dir: &Path) -> (CPU, Result<(), Vec<Diagnostic>>) {
+        let mut cpu = CPU::new();
+        cpu.init(10).unwrap();
+        cpu.quiet = true;
+        let loaded = cpu.load_file(dir.join("main.ben"));
+        fs::remove_dir_all(dir).unwrap();
+        (cpu, loaded)
+    }
+
+    #[test]
+    fn imported_blocks_live_under_the_file_stem() {
+        let dir = write_files(
+            "namespaces",
+            &[
+                (
+                    "main.ben",
+                    "import \"math.ben\"\nblock main:\nrun math::double\nend\n",
+                ),
+                (
+                    "math.ben",
+                    "block double:\nrun inner\nend\nblock inner:\nmov r1, 2\nend\n",
+                ),
+            ],
+        );
+        let (mut cpu, loaded) = load(&dir);
+        loaded.unwrap();
+        assert!(cpu.blocks.contains_key("math::inner"));
+        cpu.exec(None).unwrap();
+        assert_eq!(cpu.registers[1], crate::venobjects::VenObjects::Int(2));
+    }
+
+    #[test]
+    fn a_file_imported_twice_is_loaded_once() {
+        let dir = write_files(
+            "diamond",
+            &[
+                (
+                    "main.ben",
+                    "import \"left.ben\"\nimport \"right.ben\"\nblock main:\nend\n",
+                ),
+                ("left.ben", "import \"shared.ben\"\nblock go:\nend\n"),
+                ("right.ben", "import \"shared.ben\"\nblock go:\nend\n"),
+                ("shared.ben", "block helper:\nend\n"),
+            ],
+        );
+        let (cpu, loaded) = load(&dir);
+        loaded.unwrap();
+        let mut names: Vec<&String> = cpu.blocks.keys().collect();
+        names.sort();
+        assert_eq!(names, ["left::go", "main", "right::go", "shared::helper"]);
+    }
+
+    #[test]
+    fn import_cycles_are_reported() {
+        let dir = write_files(
+            "cycle",
+            &[
+                ("main.ben", "import \"other.ben\"\nblock main:\nend\n"),
+                ("other.ben", "import \"main.ben\"\nblock go:\nend\n"),
+            ],
+        );
+        let errors = load(&dir).1.unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, Error::IMPORT_CYCLE);
+        assert!(errors[0].message.starts_with("import cycle: "));
+    }
+
+    #[test]
+    fn missing_imports_name_the_missing_file() {
+        let dir = write_files(
+            "missing",
+            &[("main.ben", "import \"gone.ben\"\nblock main:\nend\n")],
+        );
+        let errors = load(&dir).1.unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, Error::CANNOT_READ_FILE);
+        assert!(errors[0]
+            .file
+            .as_deref()
+            .is_some_and(|f| f.ends_with("gone.ben")));
+    }
+}
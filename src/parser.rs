@@ -0,0 +1,524 @@
+use std::collections::HashMap;
+
+use crate::{
+    diagnostic::{Diagnostic, Span},
+    insts::{validate_operands, Instructions},
+    lexer::{lex, Token, TokenKind},
+    venobjects::{VenObjects, VenType},
+};
+
+/// A parsed source file.
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub imports: Vec<Import>,
+    pub globals: Vec<Global>,
+    pub blocks: Vec<Block>,
+}
+
+/// An `import "path.ben"` directive.
+#[derive(Debug, Clone)]
+pub struct Import {
+    pub path: String,
+    pub span: Span,
+}
+
+/// A `global rN, name` outside of any block.
+#[derive(Debug, Clone)]
+pub struct Global {
+    pub register: usize,
+    pub name: String,
+    pub span: Span,
+}
+
+/// A `block name: ... end` definition. `spans[i]` is the position of
+/// `body[i]` in the source.
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub name: String,
+    pub span: Span,
+    /// The `///` comment lines directly above the block header.
+    pub doc: Option<String>,
+    pub body: Vec<Instructions>,
+    pub spans: Vec<Span>,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    diagnostics: Vec<Diagnostic>,
+    /// `const NAME, value` definitions seen so far.
+    consts: HashMap<String, Instructions>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+    fn error(&mut self, span: Span, message: String) {
+        self.diagnostics
+            .push(Diagnostic::error(Some(span), message));
+    }
+    /// Skips to the start of the next line, used to recover after an error.
+    fn skip_line(&mut self) {
+        while let Some(token) = self.next() {
+            if token.kind == TokenKind::Newline {
+                break;
+            }
+        }
+    }
+    fn is_word(token: &Token, word: &str) -> bool {
+        matches!(&token.kind, TokenKind::Word(w) if w.eq_ignore_ascii_case(word))
+    }
+    /// Whether `body` ends in `declare rN, name`, which may take a type.
+    fn ends_with_declare(body: &[Instructions]) -> bool {
+        matches!(
+            body,
+            [
+                ..,
+                Instructions::DECLARE | Instructions::DECLARE_RO | Instructions::GLOBAL,
+                _,
+                Instructions::IDENT(_)
+            ]
+        )
+    }
+    fn program(&mut self) -> Program {
+        let mut program = Program::default();
+        let mut doc: Vec<String> = vec![];
+        while let Some(token) = self.next() {
+            match &token.kind {
+                TokenKind::Newline | TokenKind::Semicolon | TokenKind::Comment(_) => {}
+                TokenKind::Doc(line) => doc.push(line.clone()),
+                _ if Self::is_word(&token, "block") => {
+                    let mut block = self.block(token.span);
+                    if !doc.is_empty() {
+                        block.doc = Some(doc.join("\n"));
+                        doc.clear();
+                    }
+                    program.blocks.push(block);
+                }
+                _ if Self::is_word(&token, "const") => {
+                    doc.clear();
+                    self.constant(token.span);
+                }
+                _ if Self::is_word(&token, "global") => {
+                    doc.clear();
+                    if let Some(global) = self.global(token.span) {
+                        program.globals.push(global);
+                    }
+                }
+                _ if Self::is_word(&token, "import") => {
+                    doc.clear();
+                    match self.next() {
+                        Some(Token {
+                            kind: TokenKind::Str(path),
+                            ..
+                        }) => program.imports.push(Import {
+                            path,
+                            span: token.span,
+                        }),
+                        _ => {
+                            self.error(token.span, "expected a quoted path after `import`".into());
+                            self.skip_line();
+                        }
+                    }
+                }
+                _ => {
+                    doc.clear();
+                    self.error(
+                        token.span,
+                        "expected `block`, `import`, `const` or `global` outside of a block".into(),
+                    );
+                    self.skip_line();
+                }
+            }
+        }
+        program
+    }
+    /// Parses the rest of a top-level `global rN, name`.
+    fn global(&mut self, span: Span) -> Option<Global> {
+        let register = match self.peek().map(|t| &t.kind) {
+            Some(TokenKind::Word(word)) => match Instructions::build_from_str(&word.to_lowercase())
+            {
+                Instructions::REG(register) => Some(register),
+                _ => None,
+            },
+            _ => None,
+        };
+        let Some(register) = register else {
+            self.error(span, "expected a register after `global`".into());
+            self.skip_line();
+            return None;
+        };
+        self.pos += 1;
+        if self.peek().is_some_and(|t| t.kind == TokenKind::Comma) {
+            self.pos += 1;
+        }
+        let Some(TokenKind::Word(name)) = self.peek().map(|t| t.kind.clone()) else {
+            self.error(span, "expected a name in `global rN, name`".into());
+            self.skip_line();
+            return None;
+        };
+        self.pos += 1;
+        Some(Global {
+            register,
+            name,
+            span,
+        })
+    }
+    /// Parses the rest of `const NAME, value`. Later uses of `NAME` are
+    /// replaced by the value while parsing.
+    fn constant(&mut self, span: Span) {
+        let name = match self.next() {
+            Some(Token {
+                kind: TokenKind::Word(name),
+                ..
+            }) => name,
+            _ => {
+                self.error(span, "expected a name after `const`".into());
+                self.pos -= 1;
+                self.skip_line();
+                return;
+            }
+        };
+        if self.peek().is_some_and(|t| t.kind == TokenKind::Comma) {
+            self.pos += 1;
+        }
+        let value = match self.next().map(|t| t.kind) {
+            Some(TokenKind::Str(stri)) => Some(Instructions::DATA(VenObjects::Str(stri))),
+            Some(TokenKind::Int(int)) => Some(Instructions::DATA(VenObjects::Int(int))),
+            Some(TokenKind::Float(float)) => Some(Instructions::DATA(VenObjects::Float(float))),
+            Some(TokenKind::Word(word)) => match Instructions::build_from_str(&word.to_lowercase())
+            {
+                Instructions::IDENT(_) => self.consts.get(&word).cloned(),
+                value @ (Instructions::TRUE | Instructions::FALSE | Instructions::DATA(_)) => {
+                    Some(value)
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        let Some(value) = value else {
+            self.error(span, format!("constant `{}` needs a literal value", name));
+            self.pos -= 1;
+            self.skip_line();
+            return;
+        };
+        if self.consts.contains_key(&name) {
+            self.error(span, format!("constant `{}` is already defined", name));
+        }
+        self.consts.insert(name, value);
+    }
+    fn block(&mut self, span: Span) -> Block {
+        let name = match self.next() {
+            Some(Token {
+                kind: TokenKind::Word(name),
+                ..
+            }) => name,
+            other => {
+                let at = other.map_or(span, |t| t.span);
+                self.error(at, "expected a block name after `block`".into());
+                self.pos -= 1;
+                String::new()
+            }
+        };
+        if self.peek().is_some_and(|t| t.kind == TokenKind::Colon) {
+            self.pos += 1;
+        }
+        let mut block = Block {
+            name,
+            span,
+            doc: None,
+            body: vec![],
+            spans: vec![],
+        };
+        loop {
+            let Some(token) = self.peek().cloned() else {
+                self.error(span, format!("block `{}` is missing `end`", block.name));
+                break;
+            };
+            if Self::is_word(&token, "block") || Self::is_word(&token, "import") {
+                self.error(span, format!("block `{}` is missing `end`", block.name));
+                break;
+            }
+            self.pos += 1;
+            let inst = match token.kind {
+                TokenKind::Newline
+                | TokenKind::Comma
+                | TokenKind::Doc(_)
+                | TokenKind::Comment(_) => continue,
+                TokenKind::Colon if Self::ends_with_declare(&block.body) => {
+                    match self.next() {
+                        Some(Token {
+                            kind: TokenKind::Word(name),
+                            span,
+                            ..
+                        }) => match VenType::from_name(&name.to_lowercase()) {
+                            Some(ven_type) => {
+                                block.body.push(Instructions::TYPE(ven_type));
+                                block.spans.push(span);
+                            }
+                            None => self.error(
+                                span,
+                                format!(
+                                    "unknown type `{}`, expected int, float, str or bool",
+                                    name
+                                ),
+                            ),
+                        },
+                        _ => {
+                            self.error(token.span, "expected a type after `:`".into());
+                            self.pos -= 1;
+                        }
+                    }
+                    continue;
+                }
+                TokenKind::Colon => {
+                    self.error(token.span, "unexpected `:` inside a block".into());
+                    continue;
+                }
+                TokenKind::Semicolon => Instructions::EOL,
+                TokenKind::Str(stri) => Instructions::DATA(VenObjects::Str(stri)),
+                TokenKind::Int(int) => Instructions::DATA(VenObjects::Int(int)),
+                TokenKind::Float(float) => Instructions::DATA(VenObjects::Float(float)),
+                TokenKind::Word(_) if Self::is_word(&token, "const") => {
+                    self.constant(token.span);
+                    continue;
+                }
+                TokenKind::Word(word) => match Instructions::build_from_str(&word.to_lowercase()) {
+                    Instructions::END => break,
+                    Instructions::IDENT(_) => match self.consts.get(&word) {
+                        Some(value) => value.clone(),
+                        None => Instructions::IDENT(word),
+                    },
+                    // `declare ro r1, name`
+                    Instructions::DECLARE
+                        if self.peek().is_some_and(|next| Self::is_word(next, "ro")) =>
+                    {
+                        self.pos += 1;
+                        Instructions::DECLARE_RO
+                    }
+                    inst => inst,
+                },
+            };
+            block.body.push(inst);
+            block.spans.push(token.span);
+        }
+        close_single_statements(&mut block);
+        let problems = check_control_flow(&block.body)
+            .into_iter()
+            .chain(validate_operands(&block.body));
+        for (index, problem) in problems {
+            let at = block.spans.get(index).copied().unwrap_or(block.span);
+            self.diagnostics.push(Diagnostic {
+                block: Some(block.name.clone()),
+                ..Diagnostic::error(Some(at), problem)
+            });
+        }
+        block
+    }
+}
+
+/// Parses a preprocessed source file. Parsing never stops at the first
+/// problem: every diagnostic found is returned alongside the program.
+pub fn parse(src: &str) -> (Program, Vec<Diagnostic>) {
+    let (tokens, diagnostics) = lex(src);
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        diagnostics,
+        consts: HashMap::new(),
+    };
+    let program = parser.program();
+    let mut diagnostics = parser.diagnostics;
+    diagnostics.sort_by_key(|d| d.span.map(|span| (span.line, span.col)));
+    (program, diagnostics)
+}
+
+/// Keeps the one-line `if cond stmt;` and `times N stmt` forms working: an
+/// `if` or `times` followed by a statement on its own line, and not closed
+/// on that line, runs that statement only. Its closer is inserted after the
+/// next `;` or at the end of the line, so a body that starts on the line of
+/// its `if` must close on that line too.
+fn close_single_statements(block: &mut Block) {
+    for open in (0..block.body.len()).rev() {
+        let closer = match block.body[open] {
+            Instructions::IF => Instructions::ENDIF,
+            Instructions::TIMES => Instructions::ENDTIMES,
+            _ => continue,
+        };
+        let line = block.spans[open].line;
+        let on_line = |i: usize| block.spans.get(i).is_some_and(|s| s.line == line);
+        let header = match block.body.get(open + 2) {
+            Some(Instructions::AS) if closer == Instructions::ENDTIMES => 4,
+            _ => 2,
+        };
+        let start = open + header;
+        if !on_line(start) || block.body[start] == Instructions::EOL {
+            continue;
+        }
+        // the closer of this construct, or the first one closing an
+        // enclosing construct, on this line
+        let mut depth = 0;
+        let closing = (start..)
+            .take_while(|&i| on_line(i))
+            .find(|&i| match block.body[i] {
+                Instructions::IF
+                | Instructions::WHILE
+                | Instructions::TIMES
+                | Instructions::FOR => {
+                    depth += 1;
+                    false
+                }
+                Instructions::ENDIF
+                | Instructions::ENDWHILE
+                | Instructions::ENDTIMES
+                | Instructions::ENDFOR => {
+                    depth -= 1;
+                    depth < 0
+                }
+                _ => false,
+            });
+        if closing.is_some_and(|i| block.body[i] == closer) {
+            continue;
+        }
+        let mut depth = 0;
+        let mut end = start;
+        while on_line(end) && Some(end) != closing {
+            match &block.body[end] {
+                Instructions::IF
+                | Instructions::WHILE
+                | Instructions::TIMES
+                | Instructions::FOR => depth += 1,
+                Instructions::ENDIF
+                | Instructions::ENDWHILE
+                | Instructions::ENDTIMES
+                | Instructions::ENDFOR => depth -= 1,
+                // `if cond a; else b;`
+                Instructions::EOL
+                    if depth == 0
+                        && !(closer == Instructions::ENDIF
+                            && block.body.get(end + 1) == Some(&Instructions::ELSE)
+                            && on_line(end + 1)) =>
+                {
+                    end += 1;
+                    break;
+                }
+                _ => {}
+            }
+            end += 1;
+        }
+        let span = block.spans[open];
+        block.body.insert(end, closer);
+        block.spans.insert(end, span);
+    }
+}
+
+/// Verifies that `if`/`else`/`endif`, `while`/`endwhile`, `times`/`endtimes`
+/// and `for`/`endfor` nest properly and that `break`/`continue` only appear
+/// inside a loop.
+fn check_control_flow(block: &[Instructions]) -> Vec<(usize, String)> {
+    let mut problems = vec![];
+    // the opener and, for an `if`, whether its `else` was seen
+    let mut open: Vec<(usize, &Instructions, bool)> = vec![];
+    for (i, token) in block.iter().enumerate() {
+        match token {
+            Instructions::IF | Instructions::WHILE | Instructions::TIMES | Instructions::FOR => {
+                open.push((i, token, false))
+            }
+            Instructions::ELSE => match open.last_mut() {
+                Some((_, Instructions::IF, seen_else)) if !*seen_else => *seen_else = true,
+                Some((_, Instructions::IF, _)) => {
+                    problems.push((i, "`if` already has an `else`".into()));
+                }
+                _ => problems.push((i, "`else` without a matching `if`".into())),
+            },
+            Instructions::ENDIF
+            | Instructions::ENDWHILE
+            | Instructions::ENDTIMES
+            | Instructions::ENDFOR => {
+                let expected = match token {
+                    Instructions::ENDIF => Instructions::IF,
+                    Instructions::ENDWHILE => Instructions::WHILE,
+                    Instructions::ENDTIMES => Instructions::TIMES,
+                    _ => Instructions::FOR,
+                };
+                if open.pop().map(|o| o.1) != Some(&expected) {
+                    problems.push((i, format!("`{}` without a matching `{}`", token, expected)));
+                }
+            }
+            Instructions::BREAK | Instructions::CONTINUE
+                if !open.iter().any(|o| {
+                    matches!(
+                        o.1,
+                        Instructions::WHILE | Instructions::TIMES | Instructions::FOR
+                    )
+                }) =>
+            {
+                problems.push((i, format!("`{}` outside of a loop", token)));
+            }
+            _ => {}
+        }
+    }
+    for (i, token, _) in open {
+        problems.push((i, format!("`{}` is never closed", token)));
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(src: &str) -> Vec<String> {
+        parse(src).1.into_iter().map(|d| d.message).collect()
+    }
+
+    #[test]
+    fn a_second_else_is_rejected() {
+        let errors = messages(
+            "block main:\n\
+             \tif true\n\
+             \t\tprintln 1\n\
+             \telse\n\
+             \t\tprintln 2\n\
+             \telse\n\
+             \t\tprintln 3\n\
+             \tendif\n\
+             end\n",
+        );
+        assert_eq!(errors, ["`if` already has an `else`"]);
+    }
+
+    #[test]
+    fn one_line_if_and_times_are_closed_at_the_end_of_the_statement() {
+        let (program, errors) = parse(
+            "block main:\n\
+             \tif acc println \"a\"; else println \"b\";\n\
+             \ttimes 2 println 1\n\
+             \tif acc println 1; println 2; endif\n\
+             end\n",
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+        let body = &program.blocks[0].body;
+        let count = |inst: Instructions| body.iter().filter(|&t| *t == inst).count();
+        assert_eq!(count(Instructions::ENDIF), 2);
+        assert_eq!(count(Instructions::ENDTIMES), 1);
+    }
+
+    #[test]
+    fn a_body_starting_on_the_if_line_must_close_on_it() {
+        let errors = messages(
+            "block main:\n\
+             \tif acc println 1\n\
+             \tprintln 2\n\
+             \tendif\n\
+             end\n",
+        );
+        assert_eq!(errors, ["`endif` without a matching `if`"]);
+    }
+}
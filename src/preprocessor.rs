@@ -0,0 +1,330 @@
+use crate::{
+    cpu::CPU,
+    diagnostic::{Diagnostic, Span},
+    error::Error,
+    lexer::toggle_quote,
+    sandbox::Family,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+struct Conditional {
+    active: bool,
+    parent_active: bool,
+    seen_else: bool,
+}
+
+impl CPU {
+    /// Runs the `#...` directive layer over a source file and returns the
+    /// text handed to the tokenizer.
+    ///
+    /// Every line starting with `#` is a directive; the trailing `!` is
+    /// optional, so `#r10!` and `#r10` are the same thing. Directive lines and
+    /// lines skipped by `#if` are replaced by empty lines so line numbers in
+    /// the output match the source up to the first `#include`. A bad
+    /// directive is reported and otherwise ignored.
+    pub fn preprocess(&mut self, src: &str, path: Option<&Path>) -> (String, Vec<Diagnostic>) {
+        let mut includes = vec![];
+        if let Some(path) = path {
+            includes.push(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+        }
+        let mut diagnostics = vec![];
+        let out = self.preprocess_with(src, path, &mut includes, &mut diagnostics);
+        (out, diagnostics)
+    }
+    fn preprocess_with(
+        &mut self,
+        src: &str,
+        path: Option<&Path>,
+        includes: &mut Vec<PathBuf>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> String {
+        let error = |line: usize, kind: Error, message: String| Diagnostic {
+            file: path.map(|path| path.display().to_string()),
+            ..Diagnostic::error(Some(Span { line, col: 1 }), message).with_kind(kind)
+        };
+        let mut out = String::new();
+        let mut conditionals: Vec<(usize, Conditional)> = vec![];
+        for (no, line) in src.lines().enumerate() {
+            let active = conditionals.last().is_none_or(|(_, c)| c.active);
+            let trimmed = line.trim();
+            let Some(directive) = trimmed.strip_prefix('#') else {
+                if active {
+                    out.push_str(&self.substitute(line));
+                }
+                out.push('\n');
+                continue;
+            };
+            let directive = directive.strip_suffix('!').unwrap_or(directive);
+            let split = directive
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(directive.len());
+            let (name, args) = (&directive[..split], directive[split..].trim());
+            let invalid = |message: &str| error(no + 1, Error::INVALID_DIRECTIVE, message.into());
+            match name {
+                "if" => {
+                    let (negate, symbol) = match args.strip_prefix('!') {
+                        Some(symbol) => (true, symbol.trim()),
+                        None => (false, args),
+                    };
+                    if symbol.is_empty() {
+                        diagnostics.push(invalid("`#if` needs a symbol"));
+                    }
+                    let defined = self
+                        .defines
+                        .get(symbol)
+                        .is_some_and(|value| value != "0" && value != "false");
+                    conditionals.push((
+                        no + 1,
+                        Conditional {
+                            active: active && (defined != negate),
+                            parent_active: active,
+                            seen_else: false,
+                        },
+                    ));
+                }
+                "else" => match conditionals.last_mut() {
+                    Some((_, cond)) if !cond.seen_else => {
+                        cond.seen_else = true;
+                        cond.active = cond.parent_active && !cond.active;
+                    }
+                    _ => diagnostics.push(invalid("`#else` without a matching `#if`")),
+                },
+                "endif" => {
+                    if conditionals.pop().is_none() {
+                        diagnostics.push(invalid("`#endif` without a matching `#if`"));
+                    }
+                }
+                _ if !active => {}
+                "r" => match args.parse::<usize>() {
+                    Ok(no_of_regs) => {
+                        if let Err(err) = self.init(no_of_regs) {
+                            let kind = match err.kind {
+                                Error::MEMORY_LIMIT => Error::MEMORY_LIMIT,
+                                _ => Error::INVALID_DIRECTIVE,
+                            };
+                            diagnostics.push(error(no + 1, kind, err.message));
+                        }
+                    }
+                    Err(_) => diagnostics.push(invalid(&format!("invalid `{}`", trimmed))),
+                },
+                "define" => {
+                    let mut parts = args.splitn(2, char::is_whitespace);
+                    let symbol = parts.next().unwrap_or_default();
+                    if symbol.is_empty() {
+                        diagnostics.push(invalid("`#define` needs a name"));
+                    } else {
+                        let value = parts.next().unwrap_or_default().trim();
+                        let value = self.substitute(value);
+                        self.defines.insert(symbol.to_string(), value);
+                    }
+                }
+                "include" => {
+                    let file = args.trim_matches('"');
+                    if file.is_empty() {
+                        diagnostics.push(invalid("`#include` needs a path"));
+                        out.push('\n');
+                        continue;
+                    }
+                    if !self.sandbox.allows(Family::FileIo) {
+                        diagnostics.push(error(
+                            no + 1,
+                            Error::SANDBOX_VIOLATION,
+                            format!(
+                                "`#include \"{}\"` is not allowed by the sandbox policy",
+                                file
+                            ),
+                        ));
+                        out.push('\n');
+                        continue;
+                    }
+                    let dir = path.and_then(Path::parent).unwrap_or_else(|| Path::new(""));
+                    let include_path = dir.join(file);
+                    let canonical =
+                        fs::canonicalize(&include_path).unwrap_or_else(|_| include_path.clone());
+                    if includes.contains(&canonical) {
+                        diagnostics.push(error(
+                            no + 1,
+                            Error::IMPORT_CYCLE,
+                            format!("`#include \"{}\"` includes itself", file),
+                        ));
+                        out.push('\n');
+                        continue;
+                    }
+                    match fs::read_to_string(&include_path) {
+                        Ok(data) => {
+                            includes.push(canonical);
+                            let included = self.preprocess_with(
+                                &data,
+                                Some(&include_path),
+                                includes,
+                                diagnostics,
+                            );
+                            includes.pop();
+                            out.push_str(&included);
+                        }
+                        Err(err) => diagnostics.push(error(
+                            no + 1,
+                            Error::CANNOT_READ_FILE,
+                            format!("cannot read {}: {}", include_path.display(), err),
+                        )),
+                    }
+                }
+                "stack" => match args.parse::<usize>() {
+                    Ok(depth) => self.limits.max_call_depth = Some(depth),
+                    Err(_) => diagnostics.push(invalid(&format!("invalid `{}`", trimmed))),
+                },
+                "strict" => self.strict = true,
+                "grow" => self.grow_registers = true,
+                _ => diagnostics.push(error(
+                    no + 1,
+                    Error::UNKNOWN_DIRECTIVE,
+                    format!("unknown directive `{}`", trimmed),
+                )),
+            }
+            out.push('\n');
+        }
+        if let Some((line, _)) = conditionals.first() {
+            diagnostics.push(error(
+                *line,
+                Error::INVALID_DIRECTIVE,
+                "unterminated `#if`".into(),
+            ));
+        }
+        out
+    }
+    /// Replaces every `#define`d name appearing as a whole word outside of
+    /// string literals with its value.
+    fn substitute(&self, line: &str) -> String {
+        if self.defines.is_empty() {
+            return line.to_string();
+        }
+        let mut out = String::new();
+        let mut word = String::new();
+        let mut quote = None;
+        for c in line.chars() {
+            if quote.is_none() && (c.is_alphanumeric() || c == '_') {
+                word.push(c);
+                continue;
+            }
+            self.flush_word(&mut word, &mut out);
+            toggle_quote(&mut quote, c);
+            out.push(c);
+        }
+        self.flush_word(&mut word, &mut out);
+        out
+    }
+    fn flush_word(&self, word: &mut String, out: &mut String) {
+        match self.defines.get(word.as_str()) {
+            Some(value) if !value.is_empty() => out.push_str(value),
+            _ => out.push_str(word),
+        }
+        word.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The non-blank lines `src` preprocesses to, with `defines` set up front.
+    fn lines(src: &str, defines: &[(&str, &str)]) -> Vec<String> {
+        let mut cpu = CPU::new();
+        for (name, value) in defines {
+            cpu.defines.insert(name.to_string(), value.to_string());
+        }
+        let (out, diagnostics) = cpu.preprocess(src, None);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(out.lines().count(), src.lines().count());
+        out.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn errors(src: &str) -> Vec<(usize, Error, String)> {
+        let (_, diagnostics) = CPU::new().preprocess(src, None);
+        diagnostics
+            .into_iter()
+            .map(|d| (d.span.map_or(0, |s| s.line), d.kind, d.message))
+            .collect()
+    }
+
+    #[test]
+    fn if_and_else_pick_lines_by_symbol() {
+        let src = "#if DEBUG\ndebug\n#else\nrelease\n#endif\n#if !DEBUG\nquiet\n#endif\n";
+        assert_eq!(lines(src, &[]), ["release", "quiet"]);
+        assert_eq!(lines(src, &[("DEBUG", "1")]), ["debug"]);
+        assert_eq!(lines(src, &[("DEBUG", "false")]), ["release", "quiet"]);
+        assert_eq!(lines(src, &[("DEBUG", "0")]), ["release", "quiet"]);
+    }
+
+    #[test]
+    fn conditionals_nest() {
+        let src =
+            "#if A\n#if B\nboth\n#else\nonly_a\n#endif\n#else\n#if B\nonly_b\n#endif\n#endif\n";
+        assert_eq!(lines(src, &[("A", "1"), ("B", "1")]), ["both"]);
+        assert_eq!(lines(src, &[("A", "1")]), ["only_a"]);
+        assert_eq!(lines(src, &[("B", "1")]), ["only_b"]);
+        assert!(lines(src, &[]).is_empty());
+    }
+
+    #[test]
+    fn define_substitutes_whole_words_outside_strings() {
+        let src = "#define SIZE 10\n#define TWICE SIZE SIZE\nmov r1, SIZE\nprintln \"SIZE\"\nSIZES TWICE\n";
+        assert_eq!(
+            lines(src, &[]),
+            ["mov r1, 10", "println \"SIZE\"", "SIZES 10 10"]
+        );
+    }
+
+    #[test]
+    fn directives_in_inactive_branches_are_ignored() {
+        let src = "#if OFF\n#define X 1\n#nonsense\n#endif\n#if X\nyes\n#endif\n";
+        assert!(lines(src, &[]).is_empty());
+    }
+
+    #[test]
+    fn reports_unbalanced_and_unknown_directives() {
+        assert_eq!(
+            errors("#if A\n\n#if B\n#endif\n"),
+            [(1, Error::INVALID_DIRECTIVE, "unterminated `#if`".into())]
+        );
+        assert_eq!(
+            errors("#if A\n#else\n#else\n#endif\n#endif\n"),
+            [
+                (
+                    3,
+                    Error::INVALID_DIRECTIVE,
+                    "`#else` without a matching `#if`".into()
+                ),
+                (
+                    5,
+                    Error::INVALID_DIRECTIVE,
+                    "`#endif` without a matching `#if`".into()
+                ),
+            ]
+        );
+        assert_eq!(
+            errors("#frobnicate!\n"),
+            [(
+                1,
+                Error::UNKNOWN_DIRECTIVE,
+                "unknown directive `#frobnicate!`".into()
+            )]
+        );
+    }
+
+    #[test]
+    fn settings_directives_configure_the_cpu() {
+        let mut cpu = CPU::new();
+        let (_, diagnostics) = cpu.preprocess("#r32!\n#stack 8!\n#strict!\n#grow!\n", None);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(cpu.registers.len(), 32);
+        assert_eq!(cpu.limits.max_call_depth, Some(8));
+        assert!(cpu.strict && cpu.grow_registers);
+    }
+}
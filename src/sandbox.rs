@@ -0,0 +1,199 @@
+use crate::{
+    cpu::CPU,
+    error::{Error, RuntimeError},
+    insts::Instructions,
+};
+use std::collections::HashSet;
+
+/// Groups of instructions a `SandboxPolicy` allows or denies together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Family {
+    /// `add`, `sub`, `mul`, `div`, `pow`, `root`.
+    Arithmetic,
+    /// `and`, `or`, `xor`, `not`, `eq`, `gt`, `lt`.
+    Logic,
+    /// `mov`, `declare`, `declare ro`, `global`, and `argc` and `argv`,
+    /// which read the arguments the host passed.
+    Memory,
+    /// Branches, loops, `yield` and `halt`.
+    Control,
+    /// `run`.
+    Call,
+    /// `print` and `println`.
+    Output,
+    /// `import` and `#include`, which read other files while loading.
+    FileIo,
+    /// `getenv`.
+    Environment,
+}
+
+impl Family {
+    pub const ALL: [Family; 8] = [
+        Family::Arithmetic,
+        Family::Logic,
+        Family::Memory,
+        Family::Control,
+        Family::Call,
+        Family::Output,
+        Family::FileIo,
+        Family::Environment,
+    ];
+    /// The family an opcode belongs to, `None` for anything that is not one.
+    pub fn of(inst: &Instructions) -> Option<Family> {
+        use Instructions::*;
+        match inst {
+            ADD | SUB | MUL | DIV | POW | ROOT => Some(Family::Arithmetic),
+            AND | OR | XOR | NOT | EQ | GT | LT => Some(Family::Logic),
+            MOV | DECLARE | DECLARE_RO | GLOBAL | ARGC | ARGV => Some(Family::Memory),
+            IF | ELSE | ENDIF | WHILE | ENDWHILE | TIMES | ENDTIMES | FOR | ENDFOR | BREAK
+            | CONTINUE | YIELD | HALT => Some(Family::Control),
+            RUN => Some(Family::Call),
+            PRINT | PRINTLN => Some(Family::Output),
+            GETENV => Some(Family::Environment),
+            _ => None,
+        }
+    }
+}
+
+/// What a script is allowed to do. The default allows everything; hosts
+/// running third-party code start from `SandboxPolicy::untrusted()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SandboxPolicy {
+    pub allowed: HashSet<Family>,
+    /// Bytes `print` and `println` may write over a whole run.
+    pub max_output: Option<usize>,
+}
+
+impl Default for SandboxPolicy {
+    fn default() -> Self {
+        SandboxPolicy {
+            allowed: Family::ALL.into_iter().collect(),
+            max_output: None,
+        }
+    }
+}
+
+impl SandboxPolicy {
+    /// No file or environment access and at most 1 MiB of output.
+    pub fn untrusted() -> Self {
+        SandboxPolicy {
+            allowed: Family::ALL
+                .into_iter()
+                .filter(|family| !matches!(family, Family::FileIo | Family::Environment))
+                .collect(),
+            max_output: Some(1 << 20),
+        }
+    }
+    pub fn allows(&self, family: Family) -> bool {
+        self.allowed.contains(&family)
+    }
+    pub fn allow(mut self, family: Family) -> Self {
+        self.allowed.insert(family);
+        self
+    }
+    pub fn deny(mut self, family: Family) -> Self {
+        self.allowed.remove(&family);
+        self
+    }
+}
+
+impl CPU {
+    /// Fails if the sandbox does not allow `inst`.
+    pub(crate) fn enforce(&self, inst: &Instructions) -> Result<(), RuntimeError> {
+        match Family::of(inst) {
+            Some(family) if !self.sandbox.allows(family) => {
+                let mnemonic = inst.descriptor().map_or("?", |d| d.mnemonic);
+                Err(Error::SANDBOX_VIOLATION.with(format!(
+                    "`{}` ({:?}) is not allowed by the sandbox policy",
+                    mnemonic, family
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+    /// Writes program output, counting it against the sandbox's output cap.
+    pub(crate) fn emit(&mut self, text: &str) -> Result<(), RuntimeError> {
+        self.output_bytes += text.len();
+        if let Some(max) = self.sandbox.max_output {
+            if self.output_bytes > max {
+                return Err(Error::OUTPUT_LIMIT.with(format!("more than {} bytes printed", max)));
+            }
+        }
+        if !self.quiet {
+            print!("{}", text);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::insts::DESCRIPTORS;
+
+    /// Loads and runs `src` under `policy`, returning the kind of error it
+    /// stops with.
+    fn stop(src: &str, policy: SandboxPolicy) -> Option<Error> {
+        let mut cpu = CPU::new();
+        cpu.init(10).unwrap();
+        cpu.quiet = true;
+        cpu.sandbox = policy;
+        if let Err(errors) = cpu.parse_instructions(src.into()) {
+            return errors.first().map(|d| d.kind);
+        }
+        cpu.exec(None).err().map(|err| err.kind)
+    }
+
+    #[test]
+    fn every_instruction_belongs_to_a_family() {
+        for descriptor in DESCRIPTORS {
+            assert!(
+                Family::of(&descriptor.inst).is_some(),
+                "`{}` has no family",
+                descriptor.mnemonic
+            );
+        }
+    }
+
+    #[test]
+    fn untrusted_scripts_cannot_read_the_environment() {
+        let src = "block main:\nmov r1, 1\nadd r1, 1\nprintln r1\ngetenv r2, \"HOME\"\nend\n";
+        assert_eq!(stop(src, SandboxPolicy::default()), None);
+        assert_eq!(
+            stop(src, SandboxPolicy::untrusted()),
+            Some(Error::SANDBOX_VIOLATION)
+        );
+    }
+
+    #[test]
+    fn denied_families_stop_the_run() {
+        let src = "block helper:\nend\nblock main:\nrun helper\nend\n";
+        let policy = SandboxPolicy::default().deny(Family::Call);
+        assert_eq!(stop(src, policy.clone()), Some(Error::SANDBOX_VIOLATION));
+        assert_eq!(stop(src, policy.allow(Family::Call)), None);
+    }
+
+    #[test]
+    fn includes_are_refused_while_loading() {
+        let src = "#include \"other.ben\"\nblock main:\nend\n";
+        assert_eq!(
+            stop(src, SandboxPolicy::untrusted()),
+            Some(Error::SANDBOX_VIOLATION)
+        );
+    }
+
+    #[test]
+    fn output_is_capped() {
+        let src = "block main:\ntimes 10\nprint \"0123456789\"\nendtimes\nend\n";
+        let policy = SandboxPolicy {
+            max_output: Some(95),
+            ..SandboxPolicy::default()
+        };
+        assert_eq!(stop(src, policy), Some(Error::OUTPUT_LIMIT));
+        let policy = SandboxPolicy {
+            max_output: Some(100),
+            ..SandboxPolicy::default()
+        };
+        assert_eq!(stop(src, policy), None);
+    }
+}
@@ -0,0 +1,652 @@
+use crate::{
+    control::{ExitStatus, Status},
+    cpu::{Frame, FrameKind, Loop, LoopKind, CPU},
+    error::{Error, RuntimeError},
+    insts::{Instructions, DESCRIPTORS},
+    json::{self, Json},
+    venobjects::{VenObjects, VenType},
+};
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Instant};
+
+/// Written into every snapshot and checked by `CPU::restore`. Bump it
+/// whenever the layout changes.
+pub const SNAPSHOT_VERSION: u64 = 2;
+
+const FORMAT: &str = "veneno-snapshot";
+
+impl CPU {
+    /// Serializes everything needed to continue the program later, possibly
+    /// on another machine: registers, `acc`, aliases and their scopes, typed
+    /// and read-only registers, blocks, the call stack with each frame's
+    /// position and open loops, the instruction and output counters, and the
+    /// entry block and script arguments.
+    ///
+    /// Limits, the sandbox policy and the cancel token belong to the host and
+    /// are not included; neither are spans, docs and files.
+    pub fn snapshot(&self) -> String {
+        let mut blocks: Vec<(&String, &Arc<Vec<Instructions>>)> = self.blocks.iter().collect();
+        blocks.sort_by_key(|(name, _)| *name);
+        let mut types: Vec<_> = self.register_types.iter().collect();
+        types.sort_by_key(|(rid, _)| **rid);
+        let mut read_only: Vec<_> = self.read_only.iter().collect();
+        read_only.sort_by_key(|(rid, _)| **rid);
+        Json::object([
+            ("format", Json::str(FORMAT)),
+            ("version", Json::number(SNAPSHOT_VERSION)),
+            (
+                "registers",
+                Json::Array(self.registers.iter().map(encode_value).collect()),
+            ),
+            ("acc", encode_value(&self.acc)),
+            ("globals", encode_aliases(&self.aliases)),
+            (
+                "scopes",
+                Json::Array(self.scopes.iter().map(encode_aliases).collect()),
+            ),
+            (
+                "types",
+                Json::Array(
+                    types
+                        .into_iter()
+                        .map(|(rid, (alias, ven_type))| {
+                            Json::Array(vec![
+                                Json::number(rid),
+                                Json::str(alias.as_str()),
+                                Json::str(ven_type.to_string()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "read_only",
+                Json::Array(
+                    read_only
+                        .into_iter()
+                        .map(|(rid, alias)| {
+                            Json::Array(vec![Json::number(rid), Json::str(alias.as_str())])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "blocks",
+                Json::Object(
+                    blocks
+                        .into_iter()
+                        .map(|(name, body)| (name.clone(), encode_body(body)))
+                        .collect(),
+                ),
+            ),
+            ("program", encode_body(&self.tokens)),
+            (
+                "frames",
+                Json::Array(self.frames.iter().map(encode_frame).collect()),
+            ),
+            ("started", Json::Bool(self.started.is_some())),
+            ("executed", Json::number(self.executed)),
+            ("output_bytes", Json::number(self.output_bytes)),
+            ("call_depth", Json::number(self.call_depth)),
+            ("strict", Json::Bool(self.strict)),
+            ("grow_registers", Json::Bool(self.grow_registers)),
+            ("exit_code", self.exit_code.map_or(Json::Null, Json::number)),
+            ("entry", Json::str(self.entry.as_str())),
+            (
+                "args",
+                Json::Array(
+                    self.args
+                        .iter()
+                        .map(|arg| Json::str(arg.as_str()))
+                        .collect(),
+                ),
+            ),
+        ])
+        .to_string()
+    }
+    /// Rebuilds a CPU from `CPU::snapshot` output. `run_for` or `resume` on
+    /// the result continues exactly where the snapshot was taken.
+    pub fn restore(src: &str) -> Result<CPU, RuntimeError> {
+        decode(src).map_err(|err| Error::INVALID_SNAPSHOT.with(err))
+    }
+    /// Runs a restored program to completion.
+    pub fn resume(&mut self) -> Result<ExitStatus, RuntimeError> {
+        while self.run_frames(0, None)? != Status::Finished {}
+        Ok(self.exit_status())
+    }
+}
+
+fn encode_aliases(aliases: &HashMap<String, usize>) -> Json {
+    let mut aliases: Vec<_> = aliases.iter().collect();
+    aliases.sort();
+    Json::Object(
+        aliases
+            .into_iter()
+            .map(|(alias, rid)| (alias.clone(), Json::number(rid)))
+            .collect(),
+    )
+}
+
+fn encode_value(value: &VenObjects) -> Json {
+    match value {
+        VenObjects::Int(num) => Json::object([("int", Json::number(num))]),
+        VenObjects::Float(float) if float.is_finite() => {
+            Json::object([("float", Json::number(format!("{:?}", float)))])
+        }
+        // JSON has no NaN or infinities
+        VenObjects::Float(float) => Json::object([("float", Json::str(float.to_string()))]),
+        VenObjects::Str(stri) => Json::object([("str", Json::str(stri.as_str()))]),
+        VenObjects::Bool(bol) => Json::object([("bool", Json::Bool(*bol))]),
+        VenObjects::Class(name, body) => Json::object([
+            ("class", Json::str(name.as_str())),
+            ("body", encode_body(body)),
+        ]),
+        VenObjects::Function(name, body) => Json::object([
+            ("function", Json::str(name.as_str())),
+            ("body", encode_body(body)),
+        ]),
+        VenObjects::Empty => Json::Null,
+    }
+}
+
+fn encode_body(body: &[Instructions]) -> Json {
+    Json::Array(body.iter().map(encode_inst).collect())
+}
+
+/// Opcodes and keywords are written as their source text, everything that
+/// carries data as a single-member object.
+fn encode_inst(inst: &Instructions) -> Json {
+    if let Some(descriptor) = inst.descriptor() {
+        return Json::str(descriptor.mnemonic);
+    }
+    match inst {
+        Instructions::REG(rid) => Json::object([("reg", Json::number(rid))]),
+        Instructions::IDENT(name) => Json::object([("ident", Json::str(name.as_str()))]),
+        Instructions::KEYWORD(word) => Json::object([("keyword", Json::str(word.as_str()))]),
+        Instructions::TYPE(ven_type) => Json::object([("type", Json::str(ven_type.to_string()))]),
+        Instructions::DATA(value) => Json::object([("data", encode_value(value))]),
+        Instructions::BLOCK(name, body) => Json::object([
+            ("block", Json::str(name.as_str())),
+            ("body", encode_body(body)),
+        ]),
+        other => Json::str(other.to_string()),
+    }
+}
+
+fn encode_frame(frame: &Frame) -> Json {
+    let kind = match frame.kind {
+        FrameKind::Top => "top",
+        FrameKind::Entry => "entry",
+        FrameKind::Call => "call",
+    };
+    Json::object([
+        ("kind", Json::str(kind)),
+        (
+            "block",
+            frame.block.as_deref().map_or(Json::Null, Json::str),
+        ),
+        ("pc", Json::number(frame.pc)),
+        ("body", encode_body(&frame.body)),
+        (
+            "loops",
+            Json::Array(frame.loops.iter().map(encode_loop).collect()),
+        ),
+    ])
+}
+
+fn encode_loop(current: &Loop) -> Json {
+    let mut members = vec![
+        ("start".to_string(), Json::number(current.start)),
+        ("end".to_string(), Json::number(current.end)),
+    ];
+    let kind = match &current.kind {
+        LoopKind::While => vec![("kind", Json::str("while"))],
+        LoopKind::Times {
+            remaining,
+            index,
+            counter,
+        } => vec![
+            ("kind", Json::str("times")),
+            ("remaining", Json::number(remaining)),
+            ("index", Json::number(index)),
+            ("counter", counter.map_or(Json::Null, Json::number)),
+        ],
+        LoopKind::For {
+            counter,
+            value,
+            end,
+            step,
+        } => vec![
+            ("kind", Json::str("for")),
+            ("counter", Json::number(counter)),
+            ("value", Json::number(value)),
+            ("limit", Json::number(end)),
+            ("step", Json::number(step)),
+        ],
+    };
+    members.extend(
+        kind.into_iter()
+            .map(|(key, value)| (key.to_string(), value)),
+    );
+    Json::Object(members)
+}
+
+fn field<'a>(json: &'a Json, key: &str) -> Result<&'a Json, String> {
+    json.get(key).ok_or_else(|| format!("missing `{}`", key))
+}
+
+fn number<T: FromStr>(json: &Json, key: &str) -> Result<T, String> {
+    field(json, key)?
+        .as_number()
+        .ok_or_else(|| format!("`{}` is not a valid number", key))
+}
+
+fn flag(json: &Json, key: &str) -> Result<bool, String> {
+    field(json, key)?
+        .as_bool()
+        .ok_or_else(|| format!("`{}` is not a boolean", key))
+}
+
+fn array<'a>(json: &'a Json, key: &str) -> Result<&'a [Json], String> {
+    field(json, key)?
+        .as_array()
+        .ok_or_else(|| format!("`{}` is not an array", key))
+}
+
+fn text<'a>(json: &'a Json, key: &str) -> Result<&'a str, String> {
+    field(json, key)?
+        .as_str()
+        .ok_or_else(|| format!("`{}` is not a string", key))
+}
+
+fn decode(src: &str) -> Result<CPU, String> {
+    let root = json::parse(src)?;
+    if root.get("format").and_then(Json::as_str) != Some(FORMAT) {
+        return Err("not a Veneno snapshot".into());
+    }
+    let version: u64 = number(&root, "version")?;
+    if version != SNAPSHOT_VERSION {
+        return Err(format!(
+            "snapshot version {} is not supported, expected {}",
+            version, SNAPSHOT_VERSION
+        ));
+    }
+    let mut cpu = CPU::new();
+    cpu.registers = array(&root, "registers")?
+        .iter()
+        .map(decode_value)
+        .collect::<Result<_, _>>()?;
+    cpu.acc = decode_value(field(&root, "acc")?)?;
+    cpu.aliases = decode_aliases(field(&root, "globals")?)?;
+    cpu.scopes = array(&root, "scopes")?
+        .iter()
+        .map(decode_aliases)
+        .collect::<Result<_, _>>()?;
+    for entry in array(&root, "types")? {
+        match entry.as_array() {
+            Some([rid, Json::Str(alias), Json::Str(ven_type)]) => {
+                let rid = rid.as_number().ok_or("invalid register in `types`")?;
+                let ven_type = VenType::from_name(ven_type)
+                    .ok_or_else(|| format!("unknown type `{}`", ven_type))?;
+                cpu.register_types.insert(rid, (alias.clone(), ven_type));
+            }
+            _ => return Err("invalid entry in `types`".into()),
+        }
+    }
+    for entry in array(&root, "read_only")? {
+        match entry.as_array() {
+            Some([rid, Json::Str(alias)]) => {
+                let rid = rid.as_number().ok_or("invalid register in `read_only`")?;
+                cpu.read_only.insert(rid, alias.clone());
+            }
+            _ => return Err("invalid entry in `read_only`".into()),
+        }
+    }
+    let blocks = field(&root, "blocks")?
+        .as_object()
+        .ok_or("`blocks` is not an object")?;
+    for (name, body) in blocks {
+        cpu.blocks
+            .insert(name.clone(), Arc::new(decode_body(body)?));
+    }
+    cpu.tokens = decode_body(field(&root, "program")?)?;
+    cpu.frames = array(&root, "frames")?
+        .iter()
+        .map(decode_frame)
+        .collect::<Result<_, _>>()?;
+    if flag(&root, "started")? {
+        cpu.started = Some(Instant::now());
+    }
+    cpu.executed = number(&root, "executed")?;
+    cpu.output_bytes = number(&root, "output_bytes")?;
+    cpu.call_depth = number(&root, "call_depth")?;
+    let calls = cpu
+        .frames
+        .iter()
+        .filter(|frame| frame.kind == FrameKind::Call)
+        .count();
+    if cpu.call_depth != calls {
+        return Err(format!(
+            "call depth {} does not match the {} call frame(s)",
+            cpu.call_depth, calls
+        ));
+    }
+    cpu.strict = flag(&root, "strict")?;
+    cpu.grow_registers = flag(&root, "grow_registers")?;
+    // absent from snapshots taken before `halt` existed
+    cpu.exit_code = match root.get("exit_code") {
+        None | Some(Json::Null) => None,
+        Some(code) => Some(
+            code.as_number()
+                .ok_or("`exit_code` is not a valid number")?,
+        ),
+    };
+    cpu.entry = text(&root, "entry")?.to_string();
+    cpu.args = array(&root, "args")?
+        .iter()
+        .map(|arg| {
+            arg.as_str()
+                .map(str::to_string)
+                .ok_or("invalid entry in `args`")
+        })
+        .collect::<Result<_, _>>()?;
+    let registers = cpu.registers.len();
+    cpu.init(registers).map_err(|err| err.to_string())?;
+    Ok(cpu)
+}
+
+fn decode_aliases(json: &Json) -> Result<HashMap<String, usize>, String> {
+    json.as_object()
+        .ok_or("aliases are not an object")?
+        .iter()
+        .map(|(alias, rid)| {
+            rid.as_number()
+                .map(|rid| (alias.clone(), rid))
+                .ok_or_else(|| format!("invalid register for alias `{}`", alias))
+        })
+        .collect()
+}
+
+fn decode_value(json: &Json) -> Result<VenObjects, String> {
+    let value = match json.as_object() {
+        None if *json == Json::Null => Some(VenObjects::Empty),
+        Some([(tag, value)]) => match (tag.as_str(), value) {
+            ("int", num) => num.as_number().map(VenObjects::Int),
+            ("float", Json::Str(special)) => special.parse().ok().map(VenObjects::Float),
+            ("float", num) => num.as_number().map(VenObjects::Float),
+            ("str", Json::Str(stri)) => Some(VenObjects::Str(stri.clone())),
+            ("bool", Json::Bool(bol)) => Some(VenObjects::Bool(*bol)),
+            _ => None,
+        },
+        // classes and functions carry a body next to their name
+        Some([(tag, Json::Str(name)), (_, body)]) if tag == "class" => {
+            Some(VenObjects::Class(name.clone(), decode_body(body)?))
+        }
+        Some([(tag, Json::Str(name)), (_, body)]) if tag == "function" => {
+            Some(VenObjects::Function(name.clone(), decode_body(body)?))
+        }
+        _ => None,
+    };
+    value.ok_or_else(|| format!("invalid value {}", json))
+}
+
+fn decode_body(json: &Json) -> Result<Vec<Instructions>, String> {
+    json.as_array()
+        .ok_or("an instruction list is not an array")?
+        .iter()
+        .map(decode_inst)
+        .collect()
+}
+
+fn decode_inst(json: &Json) -> Result<Instructions, String> {
+    if let Some(word) = json.as_str() {
+        if let Some(descriptor) = DESCRIPTORS.iter().find(|d| d.mnemonic == word) {
+            return Ok(descriptor.inst.clone());
+        }
+        return match word {
+            "acc" => Ok(Instructions::ACC),
+            "as" => Ok(Instructions::AS),
+            "true" => Ok(Instructions::TRUE),
+            "false" => Ok(Instructions::FALSE),
+            "end" => Ok(Instructions::END),
+            ";" => Ok(Instructions::EOL),
+            _ => Err(format!("unknown instruction `{}`", word)),
+        };
+    }
+    if let (Some(name), Some(body)) = (json.get("block"), json.get("body")) {
+        let name = name.as_str().ok_or("block name is not a string")?;
+        return Ok(Instructions::BLOCK(name.to_string(), decode_body(body)?));
+    }
+    let inst = match json.as_object() {
+        Some([(tag, value)]) => match (tag.as_str(), value) {
+            ("reg", rid) => rid.as_number().map(Instructions::REG),
+            ("ident", Json::Str(name)) => Some(Instructions::IDENT(name.clone())),
+            ("keyword", Json::Str(word)) => Some(Instructions::KEYWORD(word.clone())),
+            ("type", Json::Str(name)) => VenType::from_name(name).map(Instructions::TYPE),
+            ("data", value) => Some(Instructions::DATA(decode_value(value)?)),
+            _ => None,
+        },
+        _ => None,
+    };
+    inst.ok_or_else(|| format!("invalid instruction {}", json))
+}
+
+fn decode_frame(json: &Json) -> Result<Frame, String> {
+    let kind = match text(json, "kind")? {
+        "top" => FrameKind::Top,
+        "entry" => FrameKind::Entry,
+        "call" => FrameKind::Call,
+        other => return Err(format!("unknown frame kind `{}`", other)),
+    };
+    let block = match json.get("block") {
+        None | Some(Json::Null) => None,
+        Some(name) => Some(
+            name.as_str()
+                .ok_or("frame block is not a string")?
+                .to_string(),
+        ),
+    };
+    let body = decode_body(field(json, "body")?)?;
+    let pc = number(json, "pc")?;
+    if pc > body.len() {
+        return Err(format!("frame position {} is past its body", pc));
+    }
+    let loops: Vec<Loop> = array(json, "loops")?
+        .iter()
+        .map(decode_loop)
+        .collect::<Result<_, _>>()?;
+    for lp in &loops {
+        let closer = match lp.kind {
+            LoopKind::While => Instructions::ENDWHILE,
+            LoopKind::Times { .. } => Instructions::ENDTIMES,
+            LoopKind::For { .. } => Instructions::ENDFOR,
+        };
+        if lp.start > lp.end || body.get(lp.end) != Some(&closer) {
+            return Err(format!(
+                "loop {}..{} does not match its frame body",
+                lp.start, lp.end
+            ));
+        }
+    }
+    Ok(Frame {
+        kind,
+        block,
+        body: Arc::new(body),
+        pc,
+        loops,
+    })
+}
+
+fn decode_loop(json: &Json) -> Result<Loop, String> {
+    let kind = match text(json, "kind")? {
+        "while" => LoopKind::While,
+        "times" => LoopKind::Times {
+            remaining: number(json, "remaining")?,
+            index: number(json, "index")?,
+            counter: match field(json, "counter")? {
+                Json::Null => None,
+                counter => Some(counter.as_number().ok_or("invalid loop counter")?),
+            },
+        },
+        "for" => LoopKind::For {
+            counter: number(json, "counter")?,
+            value: number(json, "value")?,
+            end: number(json, "limit")?,
+            step: number(json, "step")?,
+        },
+        other => return Err(format!("unknown loop kind `{}`", other)),
+    };
+    Ok(Loop {
+        start: number(json, "start")?,
+        end: number(json, "end")?,
+        kind,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = r#"
+block square:
+    declare r1, n: int
+    mul n, n
+    mov acc, n
+end
+block start:
+    global r5, total
+    mov total, 0
+    argc r7
+    argv r6, 1
+    declare ro r6, name
+    times 3 as r2
+        mov r1, r2
+        run square
+        add total, acc
+    endtimes
+    for r3, 10, 0, -4
+        print r3
+        yield
+    endfor
+    mov r4, 1.5
+    halt 3
+end
+"#;
+
+    fn load() -> CPU {
+        let mut cpu = CPU::new();
+        cpu.init(10).unwrap();
+        cpu.quiet = true;
+        cpu.entry = "start".into();
+        cpu.args = vec!["first".into(), "second".into()];
+        cpu.parse_instructions(PROGRAM.into())
+            .unwrap_or_else(|errors| panic!("{:?}", errors));
+        cpu
+    }
+
+    #[test]
+    fn resuming_matches_an_uninterrupted_run() {
+        let mut expected = load();
+        let status = expected.exec(None).unwrap();
+        assert_eq!(status.code, Some(3));
+        for step in 1..=7 {
+            let mut cpu = load();
+            loop {
+                let finished = cpu.run_for(step).unwrap() == Status::Finished;
+                let snapshot = cpu.snapshot();
+                cpu = CPU::restore(&snapshot).unwrap();
+                cpu.quiet = true;
+                if finished {
+                    break;
+                }
+            }
+            assert_eq!(cpu.registers, expected.registers, "step {}", step);
+            assert_eq!(cpu.acc, expected.acc, "step {}", step);
+            assert_eq!(cpu.executed, expected.executed, "step {}", step);
+            assert_eq!(cpu.output_bytes, expected.output_bytes, "step {}", step);
+            assert_eq!(cpu.exit_code, Some(3), "step {}", step);
+        }
+    }
+
+    #[test]
+    fn restoring_keeps_everything_in_the_snapshot() {
+        let mut cpu = load();
+        cpu.run_for(20).unwrap();
+        let snapshot = cpu.snapshot();
+        let mut restored = CPU::restore(&snapshot).unwrap();
+        assert_eq!(restored.snapshot(), snapshot);
+        assert_eq!(restored.entry, "start");
+        assert_eq!(restored.args, cpu.args);
+        restored.quiet = true;
+        assert_eq!(restored.resume().unwrap().code, Some(3));
+    }
+
+    #[test]
+    fn rejects_invalid_snapshots() {
+        let snapshot = load().snapshot();
+        let older = snapshot.replace(
+            &format!("\"version\":{}", SNAPSHOT_VERSION),
+            "\"version\":1",
+        );
+        for src in [
+            "",
+            "{}",
+            "[1, 2]",
+            older.as_str(),
+            &snapshot[..snapshot.len() / 2],
+        ] {
+            let err = CPU::restore(src).unwrap_err();
+            assert_eq!(err.kind, Error::INVALID_SNAPSHOT, "{}", src);
+        }
+    }
+
+    #[test]
+    fn truncated_frame_bodies_fail_without_panicking() {
+        let mut cpu = CPU::new();
+        cpu.init(4).unwrap();
+        cpu.enter(FrameKind::Entry, None, Arc::new(vec![Instructions::MOV]));
+        let mut restored = CPU::restore(&cpu.snapshot()).unwrap();
+        let err = restored.resume().unwrap_err();
+        assert_eq!(err.kind, Error::INVALID_REGISTER_OR_VALUE);
+    }
+
+    #[test]
+    fn rejects_a_call_depth_that_does_not_match_the_frames() {
+        let mut cpu = CPU::new();
+        cpu.init(4).unwrap();
+        cpu.enter(FrameKind::Entry, None, Arc::default());
+        cpu.enter(FrameKind::Call, Some("helper".into()), Arc::default());
+        let snapshot = cpu.snapshot();
+        assert!(CPU::restore(&snapshot).is_ok());
+        let tampered = snapshot.replace("\"call_depth\":1", "\"call_depth\":0");
+        assert_ne!(tampered, snapshot);
+        let err = CPU::restore(&tampered).unwrap_err();
+        assert_eq!(err.kind, Error::INVALID_SNAPSHOT);
+    }
+
+    #[test]
+    fn rejects_loops_outside_their_frame_body() {
+        let body = vec![
+            Instructions::WHILE,
+            Instructions::DATA(VenObjects::Bool(true)),
+            Instructions::ENDWHILE,
+        ];
+        for (end, valid) in [(2, true), (1, false), (7, false)] {
+            let mut cpu = CPU::new();
+            cpu.init(4).unwrap();
+            cpu.enter(FrameKind::Entry, None, Arc::new(body.clone()));
+            cpu.frames[0].loops.push(Loop {
+                start: 0,
+                end,
+                kind: LoopKind::While,
+            });
+            let restored = CPU::restore(&cpu.snapshot());
+            assert_eq!(restored.is_ok(), valid, "end {}", end);
+        }
+    }
+
+    #[test]
+    fn rejects_deeply_nested_json() {
+        let err = CPU::restore(&"[".repeat(100_000)).unwrap_err();
+        assert_eq!(err.kind, Error::INVALID_SNAPSHOT);
+    }
+}
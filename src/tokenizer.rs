@@ -1,81 +1,560 @@
-use crate::{cpu::CPU, insts::Instructions};
-use std::process::exit;
+use crate::{
+    cpu::CPU,
+    diagnostic::{Diagnostic, Span},
+    error::Error,
+    insts::Instructions,
+    lexer::toggle_quote,
+    parser::{parse, Block, Program},
+};
+use std::{collections::HashMap, ops::Range, path::Path, sync::Arc};
 
 impl CPU {
-    pub fn parse_instructions(&mut self, insts: String) {
-        let mut current_token = String::new();
+    /// Parses a program from a string, registering its blocks. Every problem
+    /// found is returned at once instead of stopping at the first one.
+    pub fn parse_instructions(&mut self, insts: String) -> Result<(), Vec<Diagnostic>> {
+        let program = self.parse_source(&insts, None)?;
+        self.tokens = self.register_blocks(program.blocks)?;
+        Ok(())
+    }
+    /// Preprocesses and parses a source file, returning the problems of
+    /// both steps together.
+    pub fn parse_source(
+        &mut self,
+        src: &str,
+        path: Option<&Path>,
+    ) -> Result<Program, Vec<Diagnostic>> {
+        let (src, mut diagnostics) = self.preprocess(src, path);
+        match self.parse_program(&src) {
+            Ok(program) if !diagnostics.iter().any(Diagnostic::is_error) => Ok(program),
+            Ok(_) => Err(diagnostics),
+            Err(errors) => {
+                diagnostics.extend(errors);
+                Err(diagnostics)
+            }
+        }
+    }
+    /// Expands macros in a preprocessed source file and parses the result.
+    pub fn parse_program(&self, insts: &str) -> Result<Program, Vec<Diagnostic>> {
+        let (insts, mut diagnostics, expansions) = self.expand_macros_traced(insts);
+        let (program, parsed) = parse(&insts);
+        diagnostics.extend(
+            parsed
+                .into_iter()
+                .map(|diagnostic| locate_in_macro(diagnostic, &expansions)),
+        );
+        if diagnostics.iter().any(Diagnostic::is_error) {
+            return Err(diagnostics);
+        }
+        Ok(program)
+    }
+    /// Adds parsed blocks to `self.blocks` and returns them as the `BLOCK`
+    /// tokens `exec` walks.
+    pub fn register_blocks(
+        &mut self,
+        blocks: Vec<Block>,
+    ) -> Result<Vec<Instructions>, Vec<Diagnostic>> {
+        let mut diagnostics = vec![];
         let mut tokens = vec![];
-        let mut current_block = vec![];
-        let mut is_block = false;
-        let mut block_name: String = "".into();
-        let mut is_string = false;
-        for i in insts.chars() {
-            if current_token == "block" && !is_string {
-                is_block = true;
+        for block in blocks {
+            if self.blocks.contains_key(&block.name) {
+                diagnostics.push(Diagnostic::error(
+                    Some(block.span),
+                    format!("block `{}` is already defined", block.name),
+                ));
+                continue;
             }
-            if current_token.starts_with('#') && current_token.ends_with('!') {
-                current_token.remove(0);
-                current_token.remove(current_token.len() - 1);
-                match current_token.remove(0) {
-                    'r' => self.init(
-                        current_token
-                            .parse::<usize>()
-                            .expect("Cannot initialize registers"),
-                    ),
-                    a => {
-                        eprintln!("Invalid command: {}", a)
-                    }
-                }
-                current_token.clear();
+            tokens.push(Instructions::BLOCK(block.name.clone(), block.body.clone()));
+            self.spans.insert(block.name.clone(), block.spans);
+            if let Some(doc) = block.doc {
+                self.docs.insert(block.name.clone(), doc);
             }
-            if (i == ' ' || i == ',') && is_block && !current_token.is_empty() && !is_string {
-                current_block.push(Instructions::build_from_str(
-                    current_token.trim().to_lowercase().as_str(),
-                ));
-                current_token.clear();
-            } else if i == '"' {
-                is_string = !is_string;
-            } else if (i == '\n' || i == '\t') && is_block && !is_string {
-                if !current_token.trim().is_empty() {
-                    current_block.push(Instructions::build_from_str(
-                        current_token.trim().to_lowercase().as_str(),
+            self.blocks.insert(block.name, Arc::new(block.body));
+        }
+        let names: Vec<String> = tokens
+            .iter()
+            .filter_map(|token| match token {
+                Instructions::BLOCK(name, _) => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+        diagnostics.extend(self.check_counters(&names));
+        if diagnostics.is_empty() {
+            return Ok(tokens);
+        }
+        // leave the CPU as it was, so the blocks can be loaded again once fixed
+        for name in names {
+            self.blocks.remove(&name);
+            self.spans.remove(&name);
+            self.docs.remove(&name);
+        }
+        Err(diagnostics)
+    }
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+    line: usize,
+}
+
+impl CPU {
+    /// Expands `macro name a, b: ... endmacro` definitions inline.
+    ///
+    /// A macro is invoked by writing its name at the start of a statement,
+    /// followed by one operand per parameter. Names the body `declare`s for
+    /// itself are renamed on every expansion so two expansions never share
+    /// an alias. The output keeps the line structure of the input: definition
+    /// lines become empty and an expansion is joined onto its call line.
+    pub fn expand_macros(&self, src: &str) -> (String, Vec<Diagnostic>) {
+        let (out, diagnostics, _) = self.expand_macros_traced(src);
+        (out, diagnostics)
+    }
+    /// `expand_macros`, also returning where each expanded piece of text
+    /// was defined.
+    fn expand_macros_traced(&self, src: &str) -> (String, Vec<Diagnostic>, Vec<Expansion>) {
+        let mut macros: HashMap<String, Macro> = HashMap::new();
+        let mut lines: Vec<String> = vec![];
+        let mut current: Option<(String, Macro)> = None;
+        let mut diagnostics = vec![];
+        for (no, line) in src.lines().enumerate() {
+            let trimmed = strip_comments(line);
+            let trimmed = trimmed.trim();
+            let head = trimmed.split_whitespace().next().unwrap_or_default();
+            if head == "macro" {
+                if let Some((name, def)) = &current {
+                    diagnostics.push(macro_error(
+                        no + 1,
+                        Error::INVALID_MACRO,
+                        format!(
+                            "macro defined inside `{}` (line {}), which has no `endmacro` yet",
+                            name, def.line
+                        ),
                     ));
                 }
-                current_token.clear();
-            } else if i == ';' && is_block && !is_string {
-                if !current_token.trim().is_empty() {
-                    current_block.push(Instructions::build_from_str(
-                        current_token.trim().to_lowercase().as_str(),
-                    ));
+                match parse_macro_header(trimmed, no + 1) {
+                    Ok(header) => current = Some(header),
+                    Err(message) => {
+                        diagnostics.push(macro_error(no + 1, Error::INVALID_MACRO, message));
+                        // the body is still skipped up to its `endmacro`
+                        current = Some((
+                            String::new(),
+                            Macro {
+                                params: vec![],
+                                body: vec![],
+                                line: no + 1,
+                            },
+                        ));
+                    }
                 }
-                current_token.clear();
-                current_block.push(Instructions::EOL);
-            } else if i == ':' && !is_string {
-                if !is_block {
-                    eprintln!("Invalid block syntax",);
-                    exit(69);
-                } else {
-                    block_name = current_token.clone();
-                    current_token.clear();
+                lines.push(String::new());
+            } else if head == "endmacro" {
+                match current.take() {
+                    Some((name, _)) if name.is_empty() => {}
+                    Some((name, def)) => match macros.get(&name) {
+                        Some(prev) => diagnostics.push(macro_error(
+                            def.line,
+                            Error::INVALID_MACRO,
+                            format!("`{}` is already defined at line {}", name, prev.line),
+                        )),
+                        None => {
+                            macros.insert(name, def);
+                        }
+                    },
+                    None => diagnostics.push(macro_error(
+                        no + 1,
+                        Error::INVALID_MACRO,
+                        "`endmacro` without `macro`".into(),
+                    )),
                 }
+                lines.push(String::new());
+            } else if let Some((_, def)) = &mut current {
+                def.body.push(strip_comments(line));
+                lines.push(String::new());
             } else {
-                current_token.push(i);
+                lines.push(line.to_string());
+            }
+        }
+        if let Some((_, def)) = current {
+            diagnostics.push(macro_error(
+                def.line,
+                Error::INVALID_MACRO,
+                "this macro has no `endmacro`".into(),
+            ));
+        }
+        if macros.is_empty() {
+            return (lines.join("\n"), diagnostics, vec![]);
+        }
+        let mut expander = Expander {
+            macros: &macros,
+            counter: 0,
+            diagnostics,
+            expansions: vec![],
+        };
+        let mut out = String::new();
+        for (no, line) in lines.iter().enumerate() {
+            out.push_str(&expander.expand_line(line, no + 1, 0, 0));
+            out.push('\n');
+        }
+        (out, expander.diagnostics, expander.expansions)
+    }
+}
+
+/// Text on line `line` of the expanded source, between the 0-based character
+/// columns `cols`, that comes from line `defined_at` of macro `name`.
+struct Expansion {
+    line: usize,
+    cols: Range<usize>,
+    name: String,
+    defined_at: usize,
+}
+
+/// Points a diagnostic inside expanded text at the macro line it came from as
+/// well, so both the call and the definition are reported.
+fn locate_in_macro(diagnostic: Diagnostic, expansions: &[Expansion]) -> Diagnostic {
+    let Some(span) = diagnostic.span else {
+        return diagnostic;
+    };
+    let innermost = expansions
+        .iter()
+        .filter(|e| e.line == span.line && e.cols.contains(&(span.col - 1)))
+        .min_by_key(|e| e.cols.len());
+    match innermost {
+        Some(expansion) => Diagnostic {
+            message: format!(
+                "{} (in macro `{}`, line {})",
+                diagnostic.message, expansion.name, expansion.defined_at
+            ),
+            ..diagnostic
+        },
+        None => diagnostic,
+    }
+}
+
+fn macro_error(line: usize, kind: Error, message: String) -> Diagnostic {
+    Diagnostic::error(Some(Span { line, col: 1 }), message).with_kind(kind)
+}
+
+fn parse_macro_header(header: &str, line: usize) -> Result<(String, Macro), String> {
+    let rest = header["macro".len()..].trim();
+    let Some(rest) = rest.strip_suffix(':') else {
+        return Err("expected `macro name a, b:`".into());
+    };
+    let mut words = rest.splitn(2, char::is_whitespace);
+    let name = words.next().unwrap_or_default().trim().to_lowercase();
+    if name.is_empty() {
+        return Err("macro has no name".into());
+    }
+    let params = words
+        .next()
+        .unwrap_or_default()
+        .split(',')
+        .map(|param| param.trim().to_lowercase())
+        .filter(|param| !param.is_empty())
+        .collect();
+    Ok((
+        name,
+        Macro {
+            params,
+            body: vec![],
+            line,
+        },
+    ))
+}
+
+/// Splits a line into statements on `;` outside of string literals, keeping
+/// the terminating `;` with each statement.
+fn split_statements(line: &str) -> Vec<String> {
+    let mut statements = vec![];
+    let mut current = String::new();
+    let mut quote = None;
+    for c in line.chars() {
+        current.push(c);
+        toggle_quote(&mut quote, c);
+        if c == ';' && quote.is_none() {
+            statements.push(current.clone());
+            current.clear();
+        }
+    }
+    if !current.is_empty() {
+        statements.push(current);
+    }
+    statements
+}
+
+fn split_operands(operands: &str) -> Vec<String> {
+    let mut out = vec![];
+    let mut current = String::new();
+    let mut quote = None;
+    for c in operands.chars() {
+        toggle_quote(&mut quote, c);
+        if quote.is_none() && (c == ',' || c.is_whitespace()) {
+            if !current.is_empty() {
+                out.push(current.clone());
+                current.clear();
             }
-            if current_token == "end" && !is_string {
-                is_block = false;
-                tokens.push(Instructions::BLOCK(
-                    block_name.clone(),
-                    current_block.clone(),
+        } else {
+            current.push(c);
+        }
+    }
+    if !current.is_empty() {
+        out.push(current);
+    }
+    out
+}
+
+struct Expander<'a> {
+    macros: &'a HashMap<String, Macro>,
+    /// Number of expansions so far, used to rename a macro's own aliases.
+    counter: usize,
+    diagnostics: Vec<Diagnostic>,
+    expansions: Vec<Expansion>,
+}
+
+impl Expander<'_> {
+    /// Expands the macro calls on `line`, whose text starts at character
+    /// column `col` of line `line_no` in the output.
+    fn expand_line(&mut self, line: &str, line_no: usize, depth: usize, col: usize) -> String {
+        let code = strip_comments(line);
+        if !split_statements(&code)
+            .iter()
+            .any(|statement| self.macros.contains_key(&statement_head(statement)))
+        {
+            return line.to_string();
+        }
+        let mut out = String::new();
+        for statement in split_statements(&code) {
+            let body = statement.trim_start();
+            let head_len = body
+                .find(|c: char| c.is_whitespace() || c == ';')
+                .unwrap_or(body.len());
+            let name = statement_head(&statement);
+            let Some(def) = self.macros.get(&name) else {
+                out.push_str(&statement);
+                continue;
+            };
+            if depth >= 64 {
+                self.diagnostics.push(macro_error(
+                    line_no,
+                    Error::MACRO_EXPANSION,
+                    format!(
+                        "`{}` expands recursively (defined at line {})",
+                        name, def.line
+                    ),
+                ));
+                continue;
+            }
+            let operands = body[head_len..].trim().trim_end_matches(';');
+            let args = split_operands(operands);
+            if args.len() != def.params.len() {
+                self.diagnostics.push(macro_error(
+                    line_no,
+                    Error::MACRO_EXPANSION,
+                    format!(
+                        "`{}` expects {} argument(s), got {} (defined at line {})",
+                        name,
+                        def.params.len(),
+                        args.len(),
+                        def.line
+                    ),
                 ));
-                self.blocks
-                    .insert(block_name.clone(), current_block.clone());
-                current_block.clear();
-                current_token.clear();
+                continue;
             }
-            if !is_string {
-                current_token = current_token.trim().to_string();
+            self.counter += 1;
+            let mut replacements: HashMap<String, String> =
+                def.params.iter().cloned().zip(args).collect();
+            for body_line in def.body.iter() {
+                for local in declared_names(body_line) {
+                    replacements
+                        .entry(local.clone())
+                        .or_insert_with(|| format!("{}__{}{}", local, name, self.counter));
+                }
+            }
+            for (index, body_line) in def.body.iter().enumerate() {
+                let substituted = replace_words(body_line, &replacements);
+                out.push(' ');
+                let start = col + out.chars().count();
+                let expanded = self.expand_line(&substituted, line_no, depth + 1, start);
+                self.expansions.push(Expansion {
+                    line: line_no,
+                    cols: start..start + expanded.chars().count(),
+                    name: name.clone(),
+                    // the body starts on the line after `macro name:`
+                    defined_at: def.line + 1 + index,
+                });
+                out.push_str(&expanded);
+            }
+        }
+        out
+    }
+}
+
+/// The lowercased first word of a statement, which names a macro when the
+/// statement is a macro call.
+fn statement_head(statement: &str) -> String {
+    let body = statement.trim_start();
+    let len = body
+        .find(|c: char| c.is_whitespace() || c == ';')
+        .unwrap_or(body.len());
+    body[..len].to_lowercase()
+}
+
+/// Removes `//`, `;;` and single-line `/* */` comments outside of string
+/// literals, since a macro body is joined onto its call line.
+fn strip_comments(line: &str) -> String {
+    let mut out = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quote = None;
+    while let Some(c) = chars.next() {
+        toggle_quote(&mut quote, c);
+        if quote.is_none() {
+            match (c, chars.peek()) {
+                ('/', Some('/')) | (';', Some(';')) => break,
+                ('/', Some('*')) => {
+                    chars.next();
+                    let mut prev = ' ';
+                    for c in chars.by_ref() {
+                        if prev == '*' && c == '/' {
+                            break;
+                        }
+                        prev = c;
+                    }
+                    out.push(' ');
+                    continue;
+                }
+                _ => {}
             }
         }
-        self.tokens = tokens;
+        out.push(c);
+    }
+    out
+}
+
+/// Returns the aliases introduced by `declare rN, name` statements on a line.
+fn declared_names(line: &str) -> Vec<String> {
+    split_statements(line)
+        .iter()
+        .filter_map(|statement| {
+            let mut operands = split_operands(statement.trim().trim_end_matches(';'));
+            if operands
+                .get(1)
+                .is_some_and(|word| word.eq_ignore_ascii_case("ro"))
+            {
+                operands.remove(1);
+            }
+            match operands.as_slice() {
+                // the name may carry a type, as in `declare r1, count: int`
+                [head, _, name, ..] if head.eq_ignore_ascii_case("declare") => {
+                    Some(name.trim_end_matches(':').to_lowercase())
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn replace_words(line: &str, replacements: &HashMap<String, String>) -> String {
+    let mut out = String::new();
+    let mut word = String::new();
+    let mut quote = None;
+    for c in line.chars() {
+        if quote.is_none() && (c.is_alphanumeric() || c == '_') {
+            word.push(c);
+            continue;
+        }
+        out.push_str(replacements.get(&word.to_lowercase()).unwrap_or(&word));
+        word.clear();
+        toggle_quote(&mut quote, c);
+        out.push(c);
+    }
+    out.push_str(replacements.get(&word.to_lowercase()).unwrap_or(&word));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::venobjects::VenObjects;
+
+    fn errors(src: &str) -> Vec<(usize, Error, String)> {
+        let mut cpu = CPU::new();
+        cpu.init(10).unwrap();
+        cpu.parse_instructions(src.into())
+            .unwrap_err()
+            .into_iter()
+            .map(|d| (d.span.map_or(0, |s| s.line), d.kind, d.message))
+            .collect()
+    }
+
+    #[test]
+    fn expansions_keep_the_line_structure() {
+        let src = "macro twice x:\nadd x, x\nendmacro\nblock main:\ntwice r1; twice r2\nend\n";
+        let (out, diagnostics) = CPU::new().expand_macros(src);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let lines: Vec<&str> = out.lines().map(str::trim).collect();
+        assert_eq!(
+            lines,
+            ["", "", "", "block main:", "add r1, r1 add r2, r2", "end"]
+        );
+    }
+
+    #[test]
+    fn aliases_declared_by_a_macro_are_renamed_per_expansion() {
+        let src = "macro store value:\ndeclare r1, slot\nmov slot, value\nendmacro\n\
+                   block main:\nstore 1\nstore 2\nend\n";
+        let (out, _) = CPU::new().expand_macros(src);
+        assert!(out.contains("declare r1, slot__store1"), "{}", out);
+        assert!(out.contains("declare r1, slot__store2"), "{}", out);
+        assert!(!out.contains("slot "), "{}", out);
+
+        let mut cpu = CPU::new();
+        cpu.init(10).unwrap();
+        cpu.quiet = true;
+        cpu.parse_instructions(src.into()).unwrap();
+        cpu.exec(None).unwrap();
+        assert_eq!(cpu.registers[1], VenObjects::Int(2));
+    }
+
+    #[test]
+    fn errors_in_an_expansion_point_at_the_call_and_the_definition() {
+        let src = "macro broken:\nmov r1\nendmacro\nblock main:\nbroken\nend\n";
+        let errors = errors(src);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        let (line, _, message) = &errors[0];
+        assert_eq!(*line, 5);
+        assert!(
+            message.ends_with("(in macro `broken`, line 2)"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn reports_bad_calls_and_definitions() {
+        let src = "macro pair a, b:\nmov a, b\nendmacro\nblock main:\npair r1\nend\n";
+        assert_eq!(
+            errors(src),
+            [(
+                5,
+                Error::MACRO_EXPANSION,
+                "`pair` expects 2 argument(s), got 1 (defined at line 1)".into()
+            )]
+        );
+        let src = "macro again:\nagain\nendmacro\nblock main:\nagain\nend\n";
+        let recursive = errors(src);
+        assert_eq!(recursive[0].0, 5);
+        assert_eq!(recursive[0].1, Error::MACRO_EXPANSION);
+        assert!(
+            recursive[0].2.contains("expands recursively"),
+            "{:?}",
+            recursive
+        );
+        assert_eq!(
+            errors("macro open:\nmov r1, 1\nblock main:\nend\n")[0],
+            (
+                1,
+                Error::INVALID_MACRO,
+                "this macro has no `endmacro`".into()
+            )
+        );
     }
 }
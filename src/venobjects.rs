@@ -1,4 +1,4 @@
-use std::process::exit;
+use std::fmt;
 
 use crate::insts::Instructions;
 
@@ -12,7 +12,64 @@ pub enum VenObjects {
     Function(String, Vec<Instructions>),
     Empty,
 }
+/// The type a register can be declared with, as in `declare r1, count: int`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VenType {
+    Int,
+    Float,
+    Str,
+    Bool,
+}
+
+impl VenType {
+    pub fn from_name(name: &str) -> Option<VenType> {
+        match name {
+            "int" => Some(VenType::Int),
+            "float" => Some(VenType::Float),
+            "str" => Some(VenType::Str),
+            "bool" => Some(VenType::Bool),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for VenType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VenType::Int => write!(f, "int"),
+            VenType::Float => write!(f, "float"),
+            VenType::Str => write!(f, "str"),
+            VenType::Bool => write!(f, "bool"),
+        }
+    }
+}
+
+/// How `print` shows a value.
+impl fmt::Display for VenObjects {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VenObjects::Int(num) => write!(f, "{}", num),
+            VenObjects::Str(stri) => write!(f, "{}", stri),
+            VenObjects::Float(float) => write!(f, "{}", float),
+            VenObjects::Bool(bol) => write!(f, "{}", bol),
+            VenObjects::Class(name, insts) => write!(f, "{}: {:?}", name, insts),
+            VenObjects::Function(name, body) => write!(f, "{}: {:?}", name, body),
+            VenObjects::Empty => write!(f, "None"),
+        }
+    }
+}
+
 impl VenObjects {
+    /// The type of this value, or `None` for `none`, classes and functions.
+    pub fn ven_type(&self) -> Option<VenType> {
+        match self {
+            VenObjects::Int(_) => Some(VenType::Int),
+            VenObjects::Float(_) => Some(VenType::Float),
+            VenObjects::Str(_) => Some(VenType::Str),
+            VenObjects::Bool(_) => Some(VenType::Bool),
+            _ => None,
+        }
+    }
     pub fn get_int(&self) -> Option<i64> {
         match self {
             VenObjects::Int(i) => Some(*i),
@@ -25,13 +82,22 @@ impl VenObjects {
             _ => None,
         }
     }
-    pub fn get_str(&self) -> String {
+    /// Bytes this value owns outside of itself.
+    pub fn heap_size(&self) -> usize {
         match self {
-            VenObjects::Str(name) => name.clone(),
-            a => {
-                eprintln!("Incorrect string: {:?}", a);
-                exit(69);
+            VenObjects::Str(stri) => stri.capacity(),
+            VenObjects::Class(name, insts) | VenObjects::Function(name, insts) => {
+                name.capacity() + insts.capacity() * std::mem::size_of::<Instructions>()
             }
+            _ => 0,
+        }
+    }
+    /// The value of an int or float as a float.
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            VenObjects::Int(i) => Some(*i as f64),
+            VenObjects::Float(float) => Some(*float),
+            _ => None,
         }
     }
     pub fn get_bool(&self) -> Option<bool> {